@@ -1,4 +1,4 @@
-use snafu::{ensure, Backtrace, ErrorCompat, ResultExt, Snafu};
+use snafu::Snafu;
 //use crate::rcksum::types::*;
 
 #[derive(Debug, Snafu)]
@@ -9,6 +9,60 @@ pub enum Error {
         limit: usize,
     },
 
+    #[snafu(display("Invalid checksum length {}: must be between 1 and {}", length, max))]
+    InvalidChecksumLength {
+        length: usize,
+        max: usize,
+    },
+
+    #[snafu(display("Invalid config: {}", reason))]
+    InvalidConfig {
+        reason: &'static str,
+    },
+
+    #[snafu(display("Block {} out of range: target has {} blocks", block_id, num_blocks))]
+    BlockOutOfRange {
+        block_id: usize,
+        num_blocks: usize,
+    },
+
+    #[snafu(display("Block {} is {} bytes long, blocksize is {}", block_id, length, blocksize))]
+    InvalidBlockLength {
+        block_id: usize,
+        length: usize,
+        blocksize: usize,
+    },
+
+    #[snafu(display("Checksum mismatch for block {}", block_id))]
+    ChecksumMismatch {
+        block_id: usize,
+    },
+
+    #[snafu(display("Blocks {:?} failed verification {} times", ranges, failures))]
+    TooManyFailures {
+        ranges: Vec<std::ops::Range<usize>>,
+        failures: usize,
+    },
+
+    #[snafu(display("Response body length mismatch: read {} bytes, expected {}", length, expected))]
+    InvalidBodyLength {
+        length: usize,
+        expected: usize,
+    },
+
+    #[snafu(display("Short write at offset {}: wrote {} of {} bytes", offset, written, expected))]
+    ShortWrite {
+        offset: usize,
+        written: usize,
+        expected: usize,
+    },
+
+    #[snafu(display("Invalid URL {}: {}", url, error))]
+    InvalidUrl {
+        url: String,
+        error: url::ParseError,
+    },
+
     #[snafu(display("I/O error: {:#?}", error))]
     Io {
         error: std::io::Error,
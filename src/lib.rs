@@ -1,4 +1,4 @@
 #![feature(test)]
-#![feature(vec_remove_item)]
 pub mod rcksum;
 pub mod error;
+pub mod location;
@@ -0,0 +1,86 @@
+use std::path::{Path, PathBuf};
+use url::Url;
+use crate::error::*;
+
+// Where a control file or a target lives: a URL, or a local path for control files read from
+// disk
+#[derive(Clone, Debug, PartialEq)]
+pub enum Location {
+    Url(Url),
+    Path(PathBuf),
+}
+
+impl Location {
+    // A location as given on the command line. Anything that doesn't parse as an absolute URL
+    // is a path, including Windows paths such as C:\foo.zsync, whose drive letter would
+    // otherwise pass for a scheme.
+    pub fn parse(location: &str) -> Location {
+        match Url::parse(location) {
+            Ok(url) if url.scheme().len() > 1 => Location::Url(url),
+            _ => Location::Path(PathBuf::from(location)),
+        }
+    }
+
+    // Resolve a URL: line of a control file loaded from here. Absolute URLs stand on their
+    // own; relative ones are taken relative to the control file, as a browser would. For a
+    // control file fetched over HTTP, this must be the URL it was finally served from, after
+    // any redirects.
+    pub fn resolve(&self, reference: &str) -> Result<Location> {
+        if let Location::Url(url) = Location::parse(reference) {
+            return Ok(Location::Url(url));
+        }
+        match self {
+            Location::Url(base) => base.join(reference).map(Location::Url).map_err(|error| Error::InvalidUrl {
+                url: reference.to_string(),
+                error,
+            }),
+            Location::Path(base) => {
+                let dir = base.parent().unwrap_or_else(|| Path::new(""));
+                Ok(Location::Path(dir.join(reference)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Location {
+        Location::Url(Url::parse(s).unwrap())
+    }
+
+    #[test]
+    fn parse() {
+        assert!(Location::parse("http://example.com/foo.zsync") == url("http://example.com/foo.zsync"));
+        assert!(Location::parse("foo.zsync") == Location::Path("foo.zsync".into()));
+        assert!(Location::parse("/srv/foo.zsync") == Location::Path("/srv/foo.zsync".into()));
+        assert!(Location::parse("C:\\foo.zsync") == Location::Path("C:\\foo.zsync".into()));
+    }
+
+    #[test]
+    fn resolve_against_url() {
+        // The base is where the control file ended up after redirects
+        let base = url("https://mirror.example.org/releases/1.0/foo.iso.zsync");
+        assert!(base.resolve("foo.iso").unwrap() == url("https://mirror.example.org/releases/1.0/foo.iso"));
+        assert!(base.resolve("../2.0/foo.iso").unwrap() == url("https://mirror.example.org/releases/2.0/foo.iso"));
+        assert!(base.resolve("/pub/foo.iso").unwrap() == url("https://mirror.example.org/pub/foo.iso"));
+        assert!(base.resolve("//cdn.example.net/foo.iso").unwrap() == url("https://cdn.example.net/foo.iso"));
+        assert!(base.resolve("foo%20bar.iso?x=1").unwrap() == url("https://mirror.example.org/releases/1.0/foo%20bar.iso?x=1"));
+        assert!(base.resolve("http://example.com/foo.iso").unwrap() == url("http://example.com/foo.iso"));
+        assert!(base.resolve("//[::1").is_err());
+    }
+
+    #[test]
+    fn resolve_against_path() {
+        let base = Location::Path("/srv/zsync/foo.iso.zsync".into());
+        assert!(base.resolve("foo.iso").unwrap() == Location::Path("/srv/zsync/foo.iso".into()));
+        assert!(base.resolve("images/foo.iso").unwrap() == Location::Path("/srv/zsync/images/foo.iso".into()));
+        assert!(base.resolve("/data/foo.iso").unwrap() == Location::Path("/data/foo.iso".into()));
+        assert!(base.resolve("http://example.com/foo.iso").unwrap() == url("http://example.com/foo.iso"));
+
+        // A control file in the current directory
+        let base = Location::Path("foo.iso.zsync".into());
+        assert!(base.resolve("foo.iso").unwrap() == Location::Path("foo.iso".into()));
+    }
+}
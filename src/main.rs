@@ -2,9 +2,9 @@ use zsync::rcksum::map::*;
 use zsync::rcksum::types::*;
 
 fn main() {
-    let mut map = ZBlockMap::new(255*2);
+    let mut map = ZBlockMap::new(255*3, 5).unwrap();
 
-    let my_rsums = vec![
+    let my_rsums = [
         Rsum(1, 2),
         Rsum(2, 3),
         Rsum(3, 4),
@@ -25,11 +25,11 @@ fn main() {
                     length: 5,
                 },
             },
-        );
+        ).unwrap();
     }
     for i in 0..255 {
         map.insert(
-            i,
+            255 + i,
             ZBlock {
                 rsum: my_rsums[i % 7],
                 checksum: PartialChecksum {
@@ -37,11 +37,11 @@ fn main() {
                     length: 5,
                 },
             },
-        );
+        ).unwrap();
     }
     for i in 0..255 {
         map.insert(
-            i,
+            510 + i,
             ZBlock {
                 rsum: my_rsums[i % 7],
                 checksum: PartialChecksum {
@@ -49,16 +49,16 @@ fn main() {
                     length: 5,
                 },
             },
-        );
+        ).unwrap();
     }
 
     println!("Searching for weak misses 60000 times...");
-    for i in 0..60000 {
+    for _ in 0..60000 {
         map.search_weak(Rsum(9, 9));
     }
 
     println!("Searching for strong misses 60000 times...");
-    for j in 0..300 {
+    for _ in 0..300 {
         for i in 0..200 {
             let result = map.search_weak(my_rsums[i % 7]).unwrap();
             result.get(&PartialChecksum {
@@ -69,7 +69,7 @@ fn main() {
     }
 
     println!("Searching for hits 30600000 times...");
-    for j in 0..40000 {
+    for _ in 0..40000 {
         for i in 0..255 {
             let result = map.search_weak(my_rsums[i % 7]).unwrap();
             result
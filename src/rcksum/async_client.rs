@@ -0,0 +1,198 @@
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use crate::error::*;
+use super::types::*;
+use super::map::*;
+use super::matcher::*;
+use super::driver::*;
+
+// Async counterpart of Context, for use inside a tokio runtime. It drives the same Matcher,
+// reading seeds and range responses through AsyncRead and writing through AsyncWrite.
+pub struct AsyncContext<H: StrongHash = MD4Digest, O = tokio::fs::File> {
+    matcher: Matcher<H>,
+    output: O,
+    write_buffer: Vec<u8>,
+}
+
+impl<H: StrongHash> AsyncContext<H, tokio::fs::File> {
+    // Reconstruct a target of target_len bytes into a new file at output_path
+    pub async fn create(config: Config, target_len: usize, output_path: &Path) -> Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output_path)
+            .await?;
+        file.set_len(target_len as u64).await?;
+        let mut context = AsyncContext::with_output(config, target_len.div_ceil(config.blocksize()), file)?;
+        context.matcher.set_target_len(target_len)?;
+        Ok(context)
+    }
+}
+
+impl<H: StrongHash, O: AsyncWrite + AsyncSeek + Unpin> AsyncContext<H, O> {
+    pub fn with_output(config: Config, num_blocks: usize, output: O) -> Result<Self> {
+        Ok(AsyncContext {
+            matcher: Matcher::new(config, num_blocks)?,
+            output,
+            write_buffer: Vec::new(),
+        })
+    }
+
+    // The exact length of the target, if it isn't a whole number of blocks
+    pub fn set_target_len(&mut self, target_len: usize) -> Result<()> {
+        self.matcher.set_target_len(target_len)
+    }
+
+    // Give up on the transfer once remote data for a block has failed verification this many
+    // times. By default there is no limit.
+    pub fn set_max_failures(&mut self, max_failures: usize) -> Result<()> {
+        self.matcher.set_max_failures(max_failures)
+    }
+
+    pub fn matcher(&self) -> &Matcher<H> {
+        &self.matcher
+    }
+
+    // Register the checksums of a block of the target file
+    pub fn insert_block(&mut self, id: ZBlockId, block: ZBlock<H>) -> Result<()> {
+        self.matcher.insert_block(id, block)
+    }
+
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    // Carry out the writes, merging runs of adjacent blocks into single sequential writes,
+    // then commit them to the matcher
+    async fn write_blocks(&mut self, events: &[Event<'_>]) -> Result<()> {
+        for run in plan_writes(events) {
+            self.output.seek(SeekFrom::Start(run.offset as u64)).await?;
+            self.output.write_all(run.data(&mut self.write_buffer)).await?;
+        }
+        self.output.flush().await?;
+        self.matcher.commit(events);
+        Ok(())
+    }
+
+    // Local -> Output
+    pub async fn submit_source_data(&mut self, data: &[u8]) -> Result<usize> {
+        let events = self.matcher.submit_source_data(data)?;
+        self.write_blocks(&events).await?;
+        Ok(events.len())
+    }
+
+    // Local stream -> Output
+    pub async fn submit_source_reader<R: AsyncRead + Unpin>(&mut self, mut reader: R) -> Result<usize> {
+        let mut chunks = SeedChunks::new(self.matcher.config(), READ_CHUNK_SIZE);
+        let mut got_blocks = 0;
+
+        loop {
+            let n = reader.read(chunks.unfilled()).await?;
+            if chunks.fill(n) {
+                if let Some(chunk) = chunks.chunk() {
+                    got_blocks += self.submit_source_data(chunk.data).await?;
+                }
+                if !chunks.advance() {
+                    break;
+                }
+            }
+        }
+
+        Ok(got_blocks)
+    }
+
+    // Remote -> Output
+    pub async fn submit_remote_block(&mut self, id: ZBlockId, data: &[u8]) -> Result<()> {
+        let events = match self.matcher.submit_remote_block(id, data) {
+            Err(Error::ChecksumMismatch { block_id }) => {
+                self.matcher.check_failures(&[block_id])?;
+                Err(Error::ChecksumMismatch { block_id })?
+            }
+            result => result?,
+        };
+        self.write_blocks(&events).await
+    }
+
+    // Remote -> Output, for consecutive blocks starting at start. Returns the IDs of the
+    // blocks that failed verification.
+    pub async fn submit_remote_range(&mut self, start: ZBlockId, data: &[u8]) -> Result<Vec<ZBlockId>> {
+        let (events, failed) = self.matcher.submit_remote_range(start, data)?;
+        self.write_blocks(&events).await?;
+        self.matcher.check_failures(&failed)?;
+        Ok(failed)
+    }
+
+    // Remote -> Output, reading consecutive blocks starting at start from a response body as
+    // it arrives. Returns the IDs of the blocks that failed verification.
+    pub async fn submit_remote_stream<R: AsyncRead + Unpin>(&mut self, start: ZBlockId, mut reader: R) -> Result<Vec<ZBlockId>> {
+        let mut chunks = BlockChunks::new(self.matcher.config(), start);
+        let mut failed = Vec::new();
+
+        loop {
+            let n = reader.read(chunks.unfilled()).await?;
+            if chunks.fill(n)? {
+                if let Some((start, data)) = chunks.chunk() {
+                    failed.extend(self.submit_remote_range(start, data).await?);
+                }
+                if !chunks.advance() {
+                    break;
+                }
+            }
+        }
+
+        Ok(failed)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::rcksum::test_util::*;
+
+    #[tokio::test]
+    async fn reconstruct() {
+        let mut client = AsyncContext::with_output(small_config(), 4, Cursor::new(Vec::new())).unwrap();
+        insert_small_target(|id, block| client.insert_block(id, block));
+        client.insert_block(3, block(&[3; 16])).unwrap();
+
+        let mut seed = vec![9; 5];
+        seed.extend_from_slice(&[1; 16]);
+        assert!(client.submit_source_reader(&seed[..]).await.unwrap() == 2);
+        assert!(client.matcher().needed_ranges() == vec![1..2, 3..4]);
+
+        // A range response for blocks 1 to 3, with block 3 corrupt
+        let mut body = vec![2; 16];
+        body.extend_from_slice(&[1; 16]);
+        body.extend_from_slice(&[9; 16]);
+        assert!(client.submit_remote_stream(1, &body[..]).await.unwrap() == vec![3]);
+        client.submit_remote_block(3, &[3; 16]).await.unwrap();
+        assert!(client.matcher().is_complete());
+
+        let mut expected = small_target();
+        expected.extend_from_slice(&[3; 16]);
+        assert!(client.into_output().into_inner() == expected);
+    }
+
+    #[tokio::test]
+    async fn max_failures() {
+        let mut client = AsyncContext::with_output(small_config(), 3, Cursor::new(Vec::new())).unwrap();
+        insert_small_target(|id, block| client.insert_block(id, block));
+        assert!(client.set_max_failures(0).is_err());
+        client.set_max_failures(2).unwrap();
+
+        assert!(client.submit_remote_stream(1, &[9; 16][..]).await.unwrap() == vec![1]);
+        match client.submit_remote_block(1, &[9; 16]).await {
+            Err(Error::TooManyFailures { ref ranges, failures: 2 }) if *ranges == vec![1..2] => (),
+            _ => panic!("expected block 1 to be given up on"),
+        }
+    }
+}
@@ -1,250 +1,299 @@
 use std::fs::File;
-use std::io::{Write, Seek, SeekFrom};
+use std::io::{ErrorKind, Read};
 use std::path::Path;
-use std::convert::TryInto;
 use crate::error::*;
 use super::types::*;
 use super::map::*;
-use super::data_window::*;
-
-#[derive(Copy, Clone)]
-pub struct Config {
-    seq_matches: usize, // Must be > 0
-    checksum_bytes: usize,
-    blocksize: usize,
+use super::matcher::*;
+use super::output::*;
+use super::driver::*;
+
+// Seeds are only split across threads into segments of at least this size
+const PARALLEL_SEGMENT_MIN: usize = 16 << 20;
+
+// Runs shorter than this are cheaper to write than to hand to copy_file_range
+const COPY_RANGE_MIN: usize = 64 * 1024;
+
+// The seed file behind a buffer being scanned, so matched runs can be copied from the file
+// directly instead of being written out of the buffer
+struct SeedSource<'a> {
+    file: &'a File,
+    buf: &'a [u8],
+    file_offset: usize,
 }
 
-struct Context {
-    config: Config,
-    rsums: [Rsum; 2],
-    num_blocks: usize,
-    skip_bytes: usize,
-    offset: usize,
-    blockmap: ZBlockMap,
-    file: File,
-    blockshift: i32,
-    next_match: Option<ZBlockId>,
+impl<'a> SeedSource<'a> {
+    // Position in the seed file of a contiguous run, unless it isn't all within buf, e.g. it
+    // takes in the padding after the end of the seed
+    fn offset_of(&self, run: &WriteRun) -> Option<usize> {
+        let start = run.parts[0].as_ptr() as usize;
+        let buf = self.buf.as_ptr() as usize;
+        if start < buf || start + run.len > buf + self.buf.len() {
+            return None;
+        }
+        Some(self.file_offset + (start - buf))
+    }
 }
 
-impl Context {
-    pub fn new(config: Config, num_blocks: usize, output_path: &Path) -> Result<Self> {
-        assert!(config.seq_matches >= 1);
+// Driver around the sans-IO Matcher, writing matched data to an Output
+pub struct Context<H: StrongHash = MD4Digest, O: Output = File> {
+    matcher: Matcher<H>,
+    output: O,
+    write_buffer: Vec<u8>,
+    threads: usize,
+    segment_min: usize,
+}
 
+impl<H: StrongHash> Context<H, File> {
+    // Reconstruct a target of target_len bytes into a new file at output_path
+    pub fn new(config: Config, target_len: usize, output_path: &Path) -> Result<Self> {
         // Create an empty file to fill in
-        let mut file = File::create(output_path)?;
-        for _i in 0..num_blocks * config.blocksize {
-            file.write(&[0])?;
-        }
+        let file = create_file(output_path, target_len)?;
+        let mut context = Context::with_output(config, target_len.div_ceil(config.blocksize), file)?;
+        context.set_target_len(target_len)?;
+        Ok(context)
+    }
+}
 
+impl<H: StrongHash, O: Output> Context<H, O> {
+    pub fn with_output(config: Config, num_blocks: usize, output: O) -> Result<Self> {
         Ok(Context {
-            config,
-            rsums: [Rsum::default(), Rsum::default()],
-            num_blocks,
-            skip_bytes: 0,
-            offset: 0,
-            blockmap: ZBlockMap::new(num_blocks),
-            file,
-            // TODO: Calculate this properly
-            blockshift: (config.blocksize as f64).log2().round() as i32,
-            next_match: None,
+            matcher: Matcher::new(config, num_blocks)?,
+            output,
+            write_buffer: Vec::new(),
+            threads: 1,
+            segment_min: PARALLEL_SEGMENT_MIN,
         })
     }
 
-    fn write_blocks(&mut self, blocks: &[ZBlockId], data: &[u8]) -> Result<()> {
-        assert!(data.len() == self.config.blocksize);
-        dbg!(blocks);
-        for b in blocks {
-            dbg!(b);
-            // Calculate offset into the file from the block ID
-            let offset = b * self.config.blocksize;
-            dbg!(offset);
-            let file_offset = self.file.seek(SeekFrom::Start(offset.try_into().unwrap()))?;
-            assert!(file_offset as usize == offset);
-            let bytes_written = self.file.write(data)?;
-            assert!(bytes_written == data.len());
-            self.blockmap.remove_block(*b);
-        }
-        Ok(())
+    // The exact length of the target, if it isn't a whole number of blocks
+    pub fn set_target_len(&mut self, target_len: usize) -> Result<()> {
+        self.matcher.set_target_len(target_len)
+    }
+
+    // Scan large seeds on up to this many threads
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = std::cmp::max(1, threads);
     }
 
-    fn check_block_match(&mut self, data: &[u8]) -> Result<Option<Vec<ZBlockId>>> {
-        // Look up the rsum in the blockmap
-        let rsum = self.rsums[0];
-        dbg!(rsum);
+    // Only split a seed across threads into segments of at least this many bytes, 16 MiB by
+    // default. Below that, starting the threads costs more than the scan saves.
+    pub fn set_segment_min(&mut self, segment_min: usize) {
+        self.segment_min = std::cmp::max(1, segment_min);
+    }
 
-        let blockmap = &self.blockmap;
+    // Give up on the transfer once remote data for a block has failed verification this many
+    // times. By default there is no limit.
+    pub fn set_max_failures(&mut self, max_failures: usize) -> Result<()> {
+        self.matcher.set_max_failures(max_failures)
+    }
 
-        let blocks = {
-            let checksum_map = blockmap.search_weak(rsum);
+    pub fn matcher(&self) -> &Matcher<H> {
+        &self.matcher
+    }
 
-            if let Some(map) = checksum_map {
-                // Weak hash hit; calculate the MD4 of this block
-                let checksum = PartialChecksum {
-                    value: MD4Digest::calculate(data),
-                    length: self.config.checksum_bytes,
-                };
-                dbg!(checksum);
-                map.get(&checksum).clone()
-            } else {
-                return Ok(None);
-            }
-        };
+    // Register the checksums of a block of the target file
+    pub fn insert_block(&mut self, id: ZBlockId, block: ZBlock<H>) -> Result<()> {
+        self.matcher.insert_block(id, block)
+    }
 
-        // Look up the strong hash in the blockmap
-        if let Some(b) = blocks {
-            // Strong hash hit, write through all the blocks
-            dbg!(b);
-            return Ok(Some(b.clone()));
-        } else {
-            return Ok(None);
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    // Carry out the writes, merging runs of adjacent blocks into single sequential writes.
+    // Long runs that are also contiguous in the seed file are copied from it directly. The
+    // blocks are only committed to the matcher once every write has succeeded.
+    fn write_blocks(&mut self, events: &[Event], seed: Option<&SeedSource>) -> Result<()> {
+        for run in plan_writes(events) {
+            if let Some(seed) = seed {
+                if run.len >= COPY_RANGE_MIN && run.is_contiguous() {
+                    if let Some(file_offset) = seed.offset_of(&run) {
+                        if self.output.copy_from_file(run.offset, seed.file, file_offset, run.len)? {
+                            continue;
+                        }
+                    }
+                }
+            }
+            self.output.write_block_at(run.offset, run.data(&mut self.write_buffer))?;
         }
+        self.matcher.commit(events);
+        Ok(())
     }
 
     // Local -> Output
-    fn submit_source_data(&mut self, data: &[u8]) -> Result<usize> {
-        println!("Enter submit_source_data");
-        // Create a DataWindow to view the data
-        let limit = data.len() - (self.config.blocksize * self.config.seq_matches);
-        let mut data = DataWindow::new(self.config.blocksize, limit, data);
-
-        /*
-        if self.offset > 0 {
-            println!("Advancing by {} bytes!", self.skip_bytes);
-            data.advance_n_bytes(self.skip_bytes)?;
+    pub fn submit_source_data(&mut self, data: &[u8]) -> Result<usize> {
+        self.submit_source_slice(data, None)
+    }
+
+    fn submit_source_slice(&mut self, data: &[u8], seed: Option<&SeedSource>) -> Result<usize> {
+        let segments = std::cmp::min(self.threads, data.len() / self.segment_min);
+        let events = if segments > 1 {
+            self.matcher.submit_source_data_parallel(data, segments)?
         } else {
-            self.next_match = None;
-        }
+            self.matcher.submit_source_data(data)?
+        };
+        self.write_blocks(&events, seed)?;
+        Ok(events.len())
+    }
 
-        if self.offset == 0 || self.skip_bytes > 0 {
-            self.rsums[0] = Rsum::calculate(data.get_cur_block());
-            if self.config.seq_matches > 1 {
-                self.rsums[1] = Rsum::calculate(data.get_nth_block(1).unwrap());
+    // Local file -> Output. Regular files are mapped and scanned in place; anything that
+    // can't be mapped (pipes, procfs) is read through a bounded buffer instead. Either way the
+    // end of the file is padded with zeros to find a short last block of the target.
+    pub fn submit_source_file(&mut self, path: &Path) -> Result<usize> {
+        let file = File::open(path)?;
+
+        #[cfg(feature = "mmap")]
+        {
+            if let Some(map) = self.map_seed(&file) {
+                let seed = SeedSource { file: &file, buf: &map, file_offset: 0 };
+                let got_blocks = self.submit_source_slice(&map, Some(&seed))?;
+                let tail = padded_tail(self.matcher.config(), &map);
+                return Ok(got_blocks + self.submit_source_slice(&tail, None)?);
             }
         }
-        */
-        /////////////
-        self.rsums[0] = Rsum::calculate(data.get_cur_block());
-        if self.config.seq_matches > 1 {
-            self.rsums[1] = Rsum::calculate(data.get_nth_block(1).unwrap());
+
+        self.submit_source_chunks(&file, self.read_chunk_size(), Some(&file))
+    }
+
+    // Buffered seeds are read in chunks large enough to be split across every thread
+    fn read_chunk_size(&self) -> usize {
+        if self.threads > 1 {
+            std::cmp::max(READ_CHUNK_SIZE, self.threads * self.segment_min)
+        } else {
+            READ_CHUNK_SIZE
         }
-        /////////////
-        
-        self.skip_bytes = 0;
+    }
 
-        let mut got_blocks = 0;
+    #[cfg(feature = "mmap")]
+    fn map_seed(&self, file: &File) -> Option<memmap2::Mmap> {
+        let config = self.matcher.config();
+        let metadata = file.metadata().ok()?;
+        // procfs and friends report a length of 0, and seeds shorter than the window have
+        // nothing to scan in place
+        if !metadata.is_file() || (metadata.len() as usize) < config.blocksize * config.seq_matches {
+            return None;
+        }
+        unsafe { memmap2::Mmap::map(file).ok() }
+    }
 
-        // Search through until we get a block hit
-        loop {
-            println!("Considering block: {:#?}", data.get_cur_block());
-            let blocks_found = self.check_block_match(data.get_cur_block())?;
-            if let Some(b) = blocks_found {
-                self.write_blocks(&b, data.get_cur_block())?;
-                got_blocks += b.len();
-                // TODO make sure next match doesn't go over
-                //self.next_match = Some(b.iter().min().unwrap()+1);
-                println!("Matched {} blocks!", b.len());
-
-                let result = if self.config.seq_matches == 1 {
-                    println!("Incrementing by 1 block");
-                    data.advance_n_blocks(1)
-                } else {
-                    println!("Incrementing by 2 blocks");
-                    data.advance_n_blocks(2)
-                };
-
-                if result.is_err() {
-                    println!("Reached limit, exiting! {:#?}", result);
-                    break;
-                }
+    // Local stream -> Output
+    pub fn submit_source_reader<R: Read>(&mut self, reader: R) -> Result<usize> {
+        let chunk_size = self.read_chunk_size();
+        self.submit_source_chunks(reader, chunk_size, None)
+    }
 
-                self.rsums[0] = if self.config.seq_matches > 1 && self.config.seq_matches == 1 {
-                    self.rsums[1]
-                } else {
-                    Rsum::calculate(data.get_cur_block())
-                };
+    // seed_file, if given, is the file the reader is reading from
+    fn submit_source_chunks<R: Read>(&mut self, mut reader: R, chunk_size: usize, seed_file: Option<&File>) -> Result<usize> {
+        let mut chunks = SeedChunks::new(self.matcher.config(), chunk_size);
+        let mut got_blocks = 0;
 
-                if self.config.seq_matches > 1 {
-                    self.rsums[1] = Rsum::calculate(data.get_nth_block(1).unwrap());
+        loop {
+            let n = match reader.read(chunks.unfilled()) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if chunks.fill(n) {
+                if let Some(chunk) = chunks.chunk() {
+                    let seed = seed_file.map(|file| SeedSource {
+                        file,
+                        buf: &chunk.data[..chunk.seed_len],
+                        file_offset: chunk.offset,
+                    });
+                    got_blocks += self.submit_source_slice(chunk.data, seed.as_ref())?;
                 }
-            } else {
-                println!("No match!");
-                // We didn't match any data, advance the window by one byte and update the
-                // rolling checksum.
-                let oc = data.get_cur_block()[0];
-                println!("Advancing by one byte!");
-                if data.advance_byte().is_err() {
-                    println!("Hit limit, exiting!");
+                if !chunks.advance() {
                     break;
                 }
-
-                println!("Updating Rsums!");
-                let nc = {
-                    let new_block = data.get_cur_block();
-                    new_block[new_block.len() - 1]
-                };
-                self.rsums[0].update(oc, nc, self.config.blocksize as u8);
-                if self.config.seq_matches > 1 {
-                    let nnc = if let Ok(next_next_block) = data.get_nth_block(1) {
-                        next_next_block[0]
-                    } else {
-                        println!("Hit limit, exiting!");
-                        break;
-                    };
-                    self.rsums[1].update(nc, nnc, self.config.blocksize as u8);
-                }
-
-                assert!(self.rsums[0] == Rsum::calculate(data.get_cur_block()));
             }
         }
 
-        self.skip_bytes = data.get_remainder();
-        dbg!(self.skip_bytes);
-        return Ok(got_blocks);
+        Ok(got_blocks)
     }
 
     // Remote -> Output
-    fn submit_remote_block(&mut self, id: ZBlockId, data: &[u8]) -> Result<()> {
-        //assert!(data.len() == ((start - end + 1) * self.config.blocksize));
-        let checksum = PartialChecksum {
-            value: MD4Digest::calculate(data),
-            length: self.config.checksum_bytes,
+    pub fn submit_remote_block(&mut self, id: ZBlockId, data: &[u8]) -> Result<()> {
+        let events = match self.matcher.submit_remote_block(id, data) {
+            Err(Error::ChecksumMismatch { block_id }) => {
+                self.matcher.check_failures(&[block_id])?;
+                Err(Error::ChecksumMismatch { block_id })?
+            }
+            result => result?,
         };
-        dbg!(checksum);
+        self.write_blocks(&events, None)
+    }
+
+    // Remote -> Output, for consecutive blocks starting at start. Returns the IDs of the
+    // blocks that failed verification.
+    pub fn submit_remote_range(&mut self, start: ZBlockId, data: &[u8]) -> Result<Vec<ZBlockId>> {
+        let (events, failed) = self.matcher.submit_remote_range(start, data)?;
+        self.write_blocks(&events, None)?;
+        self.matcher.check_failures(&failed)?;
+        Ok(failed)
+    }
 
-        if checksum == self.blockmap.blocklist[id].checksum {
-            // Write out the good blocks that we did get
-            self.write_blocks(&vec![id], data)?;
+    // Remote -> Output, for a whole target file, e.g. when a server answers a range request
+    // with the full body. Only blocks we still need are verified and written, but the body is
+    // read to the end and must be exactly as long as the target, even once nothing is missing.
+    // Returns the IDs of the blocks that failed verification.
+    pub fn submit_remote_body<R: Read>(&mut self, mut reader: R) -> Result<Vec<ZBlockId>> {
+        let mut chunks = BlockChunks::with_len(self.matcher.config(), 0, self.matcher.target_len());
+        let mut failed = Vec::new();
+
+        loop {
+            let n = match reader.read(chunks.unfilled()) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if chunks.fill(n)? {
+                if let Some((start, data)) = chunks.chunk() {
+                    failed.extend(self.submit_remote_range(start, data)?);
+                }
+                if !chunks.advance() {
+                    break;
+                }
+            }
         }
 
-        Ok(())
+        Ok(failed)
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
 mod tests {
     use super::*;
+    use crate::rcksum::test_util::*;
+    use std::sync::Mutex;
+    use std::thread::ThreadId;
 
     #[test]
     fn rcksum_sanity() {
-        let block_1 = ZBlock {
+        let block_1: ZBlock = ZBlock {
             rsum: Rsum::calculate(&[1; 16]), //(16, 136)
             checksum: PartialChecksum {
-                value: MD4Digest::calculate(&[1; 16]).into(),
+                value: MD4Digest::calculate(&[1; 16]),
                 length: 5,
             }
         };
 
-        let block_2 = ZBlock {
+        let block_2: ZBlock = ZBlock {
             rsum: Rsum::calculate(&[2; 16]), //(32, 2)
             checksum: PartialChecksum {
-                value: MD4Digest::calculate(&[2; 16]).into(),
+                value: MD4Digest::calculate(&[2; 16]),
                 length: 5,
             }
         };
-        let block_3 = ZBlock {
+        let block_3: ZBlock = ZBlock {
             rsum: Rsum::calculate(&[3; 16]), //(48, 168)
             checksum: PartialChecksum {
-                value: MD4Digest::calculate(&[3; 16]).into(),
+                value: MD4Digest::calculate(&[3; 16]),
                 length: 5,
             }
         };
@@ -262,15 +311,12 @@ mod tests {
             block_1,
         ];
 
-        let config = Config {
-            seq_matches: 1,
-            checksum_bytes: 5,
-            blocksize: 16,
-        };
-        let mut client = Context::new(config, 30, &Path::new("./myout")).unwrap();
+        let config = small_config();
+        let path = TempPath::new("rcksum_sanity");
+        let mut client = Context::new(config, 30 * 16, &path).unwrap();
 
-        for i in 0..block_list.len() {
-            client.blockmap.insert(i, block_list[i]);
+        for (i, block) in block_list.iter().enumerate() {
+            client.matcher.insert_block(i, *block).unwrap();
         }
 
         // Try some incorrect blocks
@@ -302,4 +348,441 @@ mod tests {
         
         client.submit_source_data(&concat_vec).unwrap();
     }
+
+    // A context for SMALL_TARGET writing to output
+    fn small_context<O: Output>(output: O) -> Context<MD4Digest, O> {
+        let mut client = Context::with_output(small_config(), 3, output).unwrap();
+        insert_small_target(|id, block| client.insert_block(id, block));
+        client
+    }
+
+    fn reconstruct<O: Output>(client: &mut Context<MD4Digest, O>) {
+        let mut seed = vec![9; 3];
+        seed.extend_from_slice(&[1; 16]);
+        seed.extend_from_slice(&[9; 16]);
+        assert!(client.submit_source_data(&seed).unwrap() == 2);
+        client.submit_remote_block(1, &[2; 16]).unwrap();
+        assert!(client.matcher.is_complete());
+    }
+
+    // A file under the temp dir unique to this test run, removed when dropped
+    struct TempPath(std::path::PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            TempPath(std::env::temp_dir().join(format!("zsync_{}_{}", std::process::id(), name)))
+        }
+    }
+
+    impl std::ops::Deref for TempPath {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempPath {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn memory_output() {
+        let mut client = small_context(Vec::new());
+        reconstruct(&mut client);
+
+        let mut buf = [0; 16];
+        client.output.read_block_at(16, &mut buf).unwrap();
+        assert!(buf == [2; 16]);
+        assert!(client.output.read_block_at(usize::MAX, &mut buf).is_err());
+        assert!(client.output.write_block_at(usize::MAX, &buf).is_err());
+        assert!(client.into_output() == small_target());
+    }
+
+    #[test]
+    fn remote_range() {
+        let mut client = small_context(Vec::new());
+
+        let mut data = vec![9; 16];
+        data.extend_from_slice(&[2; 16]);
+        data.extend_from_slice(&[1; 16]);
+        assert!(client.submit_remote_range(0, &data).unwrap() == vec![0]);
+        assert!(client.submit_remote_range(0, &[1; 16]).unwrap().is_empty());
+        assert!(client.matcher().is_complete());
+        assert!(client.into_output() == small_target());
+    }
+
+    #[test]
+    fn remote_body() {
+        let mut client = small_context(Vec::new());
+
+        // Already have block 1, which the body gets wrong; it is skipped, not rewritten
+        client.submit_remote_block(1, &[2; 16]).unwrap();
+        let mut body = vec![1; 16];
+        body.extend_from_slice(&[9; 16]);
+        body.extend_from_slice(&[1; 16]);
+        assert!(client.submit_remote_body(&body[..]).unwrap().is_empty());
+        assert!(client.matcher().is_complete());
+        assert!(client.output() == &small_target());
+
+        // A body longer than the target is rejected, even when there is nothing left to write
+        body.push(1);
+        assert!(client.submit_remote_body(&body[..]).is_err());
+        assert!(client.output() == &small_target());
+        let mut client: Context<MD4Digest, Vec<u8>> = Context::with_output(small_config(), 1, Vec::new()).unwrap();
+        client.insert_block(0, block(&[1; 16])).unwrap();
+        assert!(client.submit_remote_body(&[0; 32][..]).is_err());
+
+        // A body that overruns a short last block by less than a block, or stops short of it
+        let target = random_data(4, 40);
+        for len in &[41, 47, 39, 0] {
+            let mut client = Context::with_output(small_config(), 3, Vec::new()).unwrap();
+            client.set_target_len(40).unwrap();
+            insert_target(&small_config(), &target, |id, block| client.insert_block(id, block));
+            let mut body = target.clone();
+            body.resize(*len, 0);
+            match client.submit_remote_body(&body[..]) {
+                Err(Error::InvalidBodyLength { expected: 40, .. }) => (),
+                _ => panic!("{} byte body accepted", len),
+            }
+        }
+        let mut client = Context::with_output(small_config(), 3, Vec::new()).unwrap();
+        client.set_target_len(40).unwrap();
+        insert_target(&small_config(), &target, |id, block| client.insert_block(id, block));
+        assert!(client.submit_remote_body(&target[..]).unwrap().is_empty());
+        assert!(client.into_output() == target);
+    }
+
+    #[test]
+    fn max_failures() {
+        let mut client = small_context(Vec::new());
+        assert!(client.set_max_failures(0).is_err());
+        client.set_max_failures(2).unwrap();
+
+        match client.submit_remote_block(1, &[9; 16]) {
+            Err(Error::ChecksumMismatch { block_id: 1 }) => (),
+            _ => panic!("corrupt block accepted"),
+        }
+        assert!(client.submit_remote_range(1, &[9; 32]).is_err());
+        match client.submit_remote_range(1, &[9; 32]) {
+            Err(Error::TooManyFailures { ref ranges, failures: 2 }) if *ranges == vec![1..3] => (),
+            _ => panic!("expected blocks 1 and 2 to be given up on"),
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_output() {
+        let path = TempPath::new("mmap_output");
+        let mut client = small_context(MmapOutput::create(&path, 48).unwrap());
+        reconstruct(&mut client);
+        client.output().flush().unwrap();
+        drop(client);
+
+        assert!(std::fs::read(&path).unwrap() == small_target());
+    }
+
+    #[test]
+    fn chunked_seed() {
+        let mut client = small_context(Vec::new());
+
+        // Both blocks straddle a chunk boundary
+        let mut seed = vec![9; 25];
+        seed.extend_from_slice(&[1; 16]);
+        seed.extend_from_slice(&[9; 30]);
+        seed.extend_from_slice(&[2; 16]);
+        seed.extend_from_slice(&[9; 5]);
+        assert!(client.submit_source_chunks(&seed[..], 32, None).unwrap() == 3);
+        assert!(client.into_output() == small_target());
+    }
+
+    #[test]
+    fn chunked_seq_matches() {
+        // Runs of blocks that need a pair to match carry on across chunk boundaries, right up
+        // to the last block
+        let config = Config::new(2, 5, 16).unwrap();
+        let target = random_target(&config, 64, 5);
+        let seed = [&[0; 3][..], &target].concat();
+        for chunk_size in (40..120).step_by(5) {
+            let mut client = Context::with_output(config, 64, Vec::new()).unwrap();
+            insert_target(&config, &target, |id, block| client.insert_block(id, block));
+            assert!(client.submit_source_chunks(&seed[..], chunk_size, None).unwrap() == 64);
+            assert!(client.matcher().needed_ranges().is_empty());
+            assert!(client.into_output() == target);
+        }
+    }
+
+    #[test]
+    fn seed_file() {
+        let path = TempPath::new("seed_file");
+        let mut seed = vec![9; 7];
+        seed.extend_from_slice(&[2; 16]);
+        seed.extend_from_slice(&[1; 16]);
+        std::fs::write(&path, &seed).unwrap();
+
+        let mut client = small_context(Vec::new());
+        assert!(client.submit_source_file(&path).unwrap() == 3);
+        assert!(client.into_output() == small_target());
+    }
+
+    // Counts the write calls that reach the output
+    struct CountingOutput {
+        data: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Output for CountingOutput {
+        fn write_block_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+            self.writes += 1;
+            self.data.write_block_at(offset, data)
+        }
+
+        fn read_block_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+            self.data.read_block_at(offset, buf)
+        }
+    }
+
+    // Output whose writes fail until it is told otherwise
+    struct FailingOutput {
+        data: Vec<u8>,
+        fail: bool,
+    }
+
+    impl Output for FailingOutput {
+        fn write_block_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+            if self.fail {
+                Err(std::io::Error::other("disk full"))?;
+            }
+            self.data.write_block_at(offset, data)
+        }
+
+        fn read_block_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+            self.data.read_block_at(offset, buf)
+        }
+    }
+
+    #[test]
+    fn failed_writes() {
+        let mut client = small_context(FailingOutput { data: Vec::new(), fail: true });
+
+        // Blocks whose writes failed are still needed, and are found again on a retry
+        assert!(client.submit_source_data(&[1; 16]).is_err());
+        assert!(client.submit_remote_block(1, &[2; 16]).is_err());
+        assert!(client.matcher().needed_ranges() == vec![0..3]);
+
+        client.output.fail = false;
+        assert!(client.submit_source_data(&[1; 16]).unwrap() == 2);
+        client.submit_remote_block(1, &[2; 16]).unwrap();
+        assert!(client.matcher().is_complete());
+        assert!(client.into_output().data == small_target());
+    }
+
+    #[test]
+    fn coalesced_writes() {
+        let config = small_config();
+        let num_blocks = 200;
+        let target = random_target(&config, num_blocks, 1);
+        let mut client = Context::with_output(config, num_blocks, CountingOutput { data: Vec::new(), writes: 0 }).unwrap();
+        insert_target(&config, &target, |id, block| client.insert_block(id, block));
+
+        // The seed is 99% identical to the target, with two blocks damaged
+        let mut seed = target.clone();
+        seed[50 * 16] ^= 0xff;
+        seed[150 * 16 + 3] ^= 0xff;
+        assert!(client.submit_source_data(&seed).unwrap() == num_blocks - 2);
+        client.submit_remote_block(50, &target[50 * 16..51 * 16]).unwrap();
+        client.submit_remote_block(150, &target[150 * 16..151 * 16]).unwrap();
+
+        let output = client.into_output();
+        assert!(output.data == target);
+        // One write per undamaged run plus one per remote block, instead of one per block
+        assert!(output.writes == 5);
+    }
+
+    // File output that records the bytes copy_from_file managed to copy
+    struct CopyRecordingOutput {
+        file: File,
+        copied: usize,
+    }
+
+    impl Output for CopyRecordingOutput {
+        fn write_block_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+            self.file.write_block_at(offset, data)
+        }
+
+        fn read_block_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+            self.file.read_block_at(offset, buf)
+        }
+
+        fn copy_from_file(&mut self, offset: usize, src: &File, src_offset: usize, len: usize) -> Result<bool> {
+            let copied = self.file.copy_from_file(offset, src, src_offset, len)?;
+            if copied {
+                self.copied += len;
+            }
+            Ok(copied)
+        }
+    }
+
+    #[test]
+    fn copy_range_from_seed() {
+        let config = Config::new(1, 5, 1024).unwrap();
+        let num_blocks = 128;
+        let target = random_target(&config, num_blocks, 7);
+
+        let seed_path = TempPath::new("copy_range_seed");
+        let output_path = TempPath::new("copy_range_output");
+        std::fs::write(&seed_path, &target).unwrap();
+
+        let output = CopyRecordingOutput {
+            file: create_file(&output_path, num_blocks * 1024).unwrap(),
+            copied: 0,
+        };
+        let mut client = Context::with_output(config, num_blocks, output).unwrap();
+        insert_target(&config, &target, |id, block| client.insert_block(id, block));
+        assert!(client.submit_source_file(&seed_path).unwrap() == num_blocks);
+        // The whole seed is one contiguous run, so it should all have gone through
+        // copy_file_range rather than write_block_at
+        #[cfg(target_os = "linux")]
+        assert!(client.output().copied == target.len());
+        drop(client);
+
+        assert!(std::fs::read(&output_path).unwrap() == target);
+    }
+
+    // MD4, noting the threads that compute it, to tell whether a scan really ran in parallel
+    #[derive(Copy, Clone, Debug, Default, PartialEq)]
+    struct ThreadedMD4(MD4Digest);
+
+    static HASH_THREADS: Mutex<Vec<ThreadId>> = Mutex::new(Vec::new());
+
+    impl StrongHash for ThreadedMD4 {
+        const LENGTH: usize = MD4Digest::LENGTH;
+
+        fn calculate(data: &[u8]) -> Self {
+            let id = std::thread::current().id();
+            let mut threads = HASH_THREADS.lock().unwrap();
+            if !threads.contains(&id) {
+                threads.push(id);
+            }
+            ThreadedMD4(MD4Digest::calculate(data))
+        }
+
+        fn as_bytes(&self) -> &[u8] {
+            self.0.as_bytes()
+        }
+    }
+
+    #[test]
+    fn parallel_seed_file() {
+        let config = Config::new(1, 5, 1024).unwrap();
+        let num_blocks = 256;
+        let target = random_target(&config, num_blocks, 9);
+        let path = TempPath::new("parallel_seed_file");
+        std::fs::write(&path, &target).unwrap();
+
+        let setup = || {
+            let mut client: Context<ThreadedMD4, Vec<u8>> = Context::with_output(config, num_blocks, Vec::new()).unwrap();
+            insert_target(&config, &target, |id, block| {
+                client.insert_block(id, ZBlock {
+                    rsum: block.rsum,
+                    checksum: PartialChecksum {
+                        value: ThreadedMD4(block.checksum.value),
+                        length: block.checksum.length,
+                    },
+                })
+            });
+            client.set_threads(4);
+            // Small enough that the 256 KiB seed is split across all threads
+            client.set_segment_min(16 * 1024);
+            HASH_THREADS.lock().unwrap().clear();
+            client
+        };
+
+        // Mapped where the mmap feature allows, otherwise buffered
+        let mut client = setup();
+        assert!(client.submit_source_file(&path).unwrap() == num_blocks);
+        assert!(HASH_THREADS.lock().unwrap().len() > 1);
+        assert!(client.into_output() == target);
+
+        // The buffered path reads chunks big enough to scan in parallel too
+        let mut client = setup();
+        assert!(client.read_chunk_size() >= 4 * 16 * 1024);
+        let file = File::open(&path).unwrap();
+        let chunk_size = client.read_chunk_size();
+        assert!(client.submit_source_chunks(&file, chunk_size, Some(&file)).unwrap() == num_blocks);
+        assert!(HASH_THREADS.lock().unwrap().len() > 1);
+        assert!(client.into_output() == target);
+    }
+
+    #[test]
+    fn short_target() {
+        let config = small_config();
+        let mut target = vec![7; 10];
+        target.resize(16, 0);
+
+        // Found in a seed: the block's zero padding isn't written
+        let path = TempPath::new("short_target");
+        let mut client: Context = Context::new(config, 10, &path).unwrap();
+        client.insert_block(0, block(&target)).unwrap();
+        assert!(client.submit_source_data(&target).unwrap() == 1);
+        drop(client);
+        assert!(std::fs::read(&path).unwrap() == vec![7; 10]);
+
+        // Fetched remotely
+        let mut client: Context = Context::new(config, 10, &path).unwrap();
+        client.insert_block(0, block(&target)).unwrap();
+        assert!(client.submit_remote_block(0, &target).is_err());
+        client.submit_remote_block(0, &[7; 10]).unwrap();
+        assert!(client.matcher().is_complete());
+        drop(client);
+        assert!(std::fs::read(&path).unwrap() == vec![7; 10]);
+    }
+
+    #[test]
+    fn unpadded_seed() {
+        // A seed identical to a target that ends in a short block
+        let config = small_config();
+        let target = random_data(3, 40);
+        let path = TempPath::new("unpadded_seed");
+        std::fs::write(&path, &target).unwrap();
+
+        let setup = || {
+            let mut client = Context::with_output(config, 3, Vec::new()).unwrap();
+            client.set_target_len(40).unwrap();
+            insert_target(&config, &target, |id, block| client.insert_block(id, block));
+            client
+        };
+
+        let mut client = setup();
+        assert!(client.submit_source_file(&path).unwrap() == 3);
+        assert!(client.matcher().is_complete());
+        assert!(client.into_output() == target);
+
+        let mut client = setup();
+        assert!(client.submit_source_reader(&target[..]).unwrap() == 3);
+        assert!(client.matcher().is_complete());
+        assert!(client.into_output() == target);
+    }
+
+    #[test]
+    fn short_seeds() {
+        let mut client = Context::with_output(small_config(), 3, Vec::new()).unwrap();
+        client.matcher.insert_block(0, block(&[1; 16])).unwrap();
+
+        let path = TempPath::new("short_seed");
+        for len in &[0, 1, 15] {
+            std::fs::write(&path, vec![1; *len]).unwrap();
+            assert!(client.submit_source_file(&path).unwrap() == 0);
+            assert!(client.submit_source_reader(&vec![1; *len][..]).unwrap() == 0);
+        }
+        assert!(client.matcher.needed_ranges() == vec![0..3]);
+    }
 }
@@ -8,15 +8,19 @@ pub struct DataWindow<'a> {
 }
 
 impl<'a> DataWindow<'a> {
-    pub fn new(blocksize: usize, limit: usize, data: &'a [u8]) -> Self {
-        assert!(blocksize <= data.len());
-        assert!(limit <= data.len());
-        DataWindow {
+    pub fn new(blocksize: usize, limit: usize, data: &'a [u8]) -> Result<Self> {
+        if blocksize > data.len() || limit > data.len() - blocksize {
+            Err(Error::DataOutOfBounds {
+                position: limit + blocksize,
+                limit: data.len(),
+            })?;
+        }
+        Ok(DataWindow {
             pos: 0,
             blocksize,
             limit,
             data,
-        }
+        })
     }
 
     pub fn advance_byte(&mut self) -> Result<()> {
@@ -42,41 +46,30 @@ impl<'a> DataWindow<'a> {
         Ok(())
     }
 
-    pub fn advance_n_bytes(&mut self, n: usize) -> Result<()> {
-        if self.pos + n > self.limit {
-            Err(Error::DataOutOfBounds {
-                position: self.pos + n,
-                limit: self.limit
-            })?;
-        }
-        self.pos += n;
-        Ok(())
-    }
-
-    pub fn get_cur_block(&mut self) -> &[u8] {
+    pub fn get_cur_block(&self) -> &'a [u8] {
         &self.data[self.pos..self.pos+self.blocksize]
     }
 
-    pub fn get_nth_block(&mut self, n: usize) -> Result<&[u8]> {
-        let newpos = self.pos + n*self.blocksize ;
-        if newpos > self.limit {
+    // The block just before the current one, if the window has moved far enough for there to be
+    // one
+    pub fn get_prev_block(&self) -> Result<&'a [u8]> {
+        if self.pos < self.blocksize {
             Err(Error::DataOutOfBounds {
-                position: newpos,
-                limit: self.limit
+                position: self.pos,
+                limit: self.blocksize,
             })?;
         }
-        dbg!(self.data.len());
-        dbg!(self.pos);
-        dbg!(newpos);
-        dbg!(newpos+self.blocksize);
-        Ok(&self.data[newpos..newpos+self.blocksize])
+        Ok(&self.data[self.pos - self.blocksize..self.pos])
     }
 
-    pub fn get_remainder(&self) -> usize {
-        self.limit - self.pos
-    }
-
-    pub fn is_at_limit(&self) -> bool {
-        self.pos == self.limit
+    pub fn get_nth_block(&self, n: usize) -> Result<&'a [u8]> {
+        let newpos = self.pos + n * self.blocksize;
+        if newpos + self.blocksize > self.data.len() {
+            Err(Error::DataOutOfBounds {
+                position: newpos + self.blocksize,
+                limit: self.data.len(),
+            })?;
+        }
+        Ok(&self.data[newpos..newpos + self.blocksize])
     }
 }
@@ -0,0 +1,321 @@
+use super::map::ZBlockId;
+use super::matcher::{Config, Event};
+use crate::error::*;
+
+// Sans-IO pieces shared by Context and AsyncContext: how seeds and response bodies are cut
+// into chunks, and how a batch of writes is laid out. The drivers only do the reading and
+// writing.
+
+// How much of a seed or response body to hold in memory at once
+pub const READ_CHUNK_SIZE: usize = 1 << 20;
+
+// Upper bound on a single coalesced write
+pub const WRITE_BUFFER_SIZE: usize = 1 << 20;
+
+// Writes to adjacent offsets, merged so they can go out as one sequential write
+pub struct WriteRun<'a> {
+    pub offset: usize,
+    pub len: usize,
+    pub parts: Vec<&'a [u8]>,
+}
+
+impl<'a> WriteRun<'a> {
+    // Whether the parts follow each other in memory, e.g. consecutive blocks of a seed buffer
+    pub fn is_contiguous(&self) -> bool {
+        self.parts.windows(2).all(|w| w[0].as_ptr() as usize + w[0].len() == w[1].as_ptr() as usize)
+    }
+
+    // The data of the run, gathered into buf unless it is a single part
+    pub fn data<'b>(&'b self, buf: &'b mut Vec<u8>) -> &'b [u8] {
+        if let [part] = self.parts[..] {
+            return part;
+        }
+        buf.clear();
+        for part in &self.parts {
+            buf.extend_from_slice(part);
+        }
+        buf
+    }
+}
+
+// The writes of a batch of events in file order, with runs of adjacent blocks merged up to
+// WRITE_BUFFER_SIZE
+pub fn plan_writes<'a>(events: &[Event<'a>]) -> Vec<WriteRun<'a>> {
+    let mut writes: Vec<(usize, &'a [u8])> = events.iter().filter_map(|event| match event {
+        Event::Write { offset, data } => Some((*offset, *data)),
+        _ => None,
+    }).collect();
+    writes.sort_by_key(|&(offset, _)| offset);
+
+    let mut runs: Vec<WriteRun<'a>> = Vec::new();
+    for (offset, data) in writes {
+        match runs.last_mut() {
+            Some(run) if run.offset + run.len == offset && run.len + data.len() <= WRITE_BUFFER_SIZE => {
+                run.len += data.len();
+                run.parts.push(data);
+            }
+            _ => runs.push(WriteRun { offset, len: data.len(), parts: vec![data] }),
+        }
+    }
+    runs
+}
+
+// Cuts a seed that is read a piece at a time into chunks to scan. The last window of a chunk
+// can't be fully scanned, so it is carried into the next one and matches across chunk
+// boundaries are still found. The end of the seed is padded with a block of zeros, so it can
+// supply a short last block of the target, which is padded the same way.
+//
+//     loop {
+//         let n = reader.read(chunks.unfilled())?;
+//         if chunks.fill(n) {
+//             if let Some(chunk) = chunks.chunk() { /* scan chunk.data */ }
+//             if !chunks.advance() { break; }
+//         }
+//     }
+pub struct SeedChunks {
+    buf: Vec<u8>,
+    blocksize: usize,
+    window: usize,
+    filled: usize, // Including any padding
+    padding: usize,
+    carried: usize,
+    offset: usize, // Position of buf[0] in the seed
+    eof: bool,
+}
+
+// A chunk of a seed, of which the first seed_len bytes are the seed's and the rest padding
+pub struct SeedChunk<'a> {
+    pub data: &'a [u8],
+    pub offset: usize,
+    pub seed_len: usize,
+}
+
+impl SeedChunks {
+    pub fn new(config: &Config, chunk_size: usize) -> Self {
+        let window = config.blocksize() * config.seq_matches();
+        SeedChunks {
+            buf: vec![0; std::cmp::max(chunk_size, 2 * window)],
+            blocksize: config.blocksize(),
+            window,
+            filled: 0,
+            padding: 0,
+            carried: 0,
+            offset: 0,
+            eof: false,
+        }
+    }
+
+    // Where to read the next piece of the seed
+    pub fn unfilled(&mut self) -> &mut [u8] {
+        &mut self.buf[self.filled..]
+    }
+
+    // Record that n bytes were read into unfilled(), 0 meaning the end of the seed. Returns
+    // true once a chunk is ready.
+    pub fn fill(&mut self, n: usize) -> bool {
+        self.filled += n;
+        if n == 0 && !self.eof {
+            self.eof = true;
+            // An empty seed has nothing to pad
+            if self.offset + self.filled > 0 {
+                self.buf.truncate(self.filled);
+                self.buf.resize(self.filled + self.blocksize, 0);
+                self.filled += self.blocksize;
+                self.padding = self.blocksize;
+            }
+        }
+        self.eof || self.filled == self.buf.len()
+    }
+
+    // The ready chunk, unless it holds nothing new or is too short to contain a match
+    pub fn chunk(&self) -> Option<SeedChunk<'_>> {
+        if self.filled > self.carried && self.filled >= self.window {
+            Some(SeedChunk {
+                data: &self.buf[..self.filled],
+                offset: self.offset,
+                seed_len: self.filled - self.padding,
+            })
+        } else {
+            None
+        }
+    }
+
+    // Move on from the ready chunk. Returns false at the end of the seed.
+    pub fn advance(&mut self) -> bool {
+        if self.eof {
+            return false;
+        }
+        let start = self.filled - self.window;
+        self.buf.copy_within(start..self.filled, 0);
+        self.offset += start;
+        self.filled = self.window;
+        self.carried = self.window;
+        true
+    }
+}
+
+// The end of a seed that was scanned in one piece, padded like the last chunk of SeedChunks,
+// to scan for a short last block. Empty for an empty seed.
+#[cfg(any(feature = "mmap", test))]
+pub fn padded_tail(config: &Config, seed: &[u8]) -> Vec<u8> {
+    if seed.is_empty() {
+        return Vec::new();
+    }
+    let window = config.blocksize() * config.seq_matches();
+    let mut tail = seed[seed.len().saturating_sub(window)..].to_vec();
+    tail.resize(tail.len() + config.blocksize(), 0);
+    tail
+}
+
+// Cuts a stream of consecutive blocks, such as a range response body, into chunks of whole
+// blocks to submit. Used like SeedChunks, except that fill() fails once the stream is known to
+// be longer or shorter than it should be.
+pub struct BlockChunks {
+    buf: Vec<u8>,
+    blocksize: usize,
+    filled: usize,
+    start: ZBlockId, // First block of the chunk
+    read: usize, // Total bytes read so far
+    expected_len: Option<usize>,
+    eof: bool,
+}
+
+impl BlockChunks {
+    pub fn new(config: &Config, start: ZBlockId) -> Self {
+        let blocksize = config.blocksize();
+        BlockChunks {
+            buf: vec![0; std::cmp::max(READ_CHUNK_SIZE / blocksize, 1) * blocksize],
+            blocksize,
+            filled: 0,
+            start,
+            read: 0,
+            expected_len: None,
+            eof: false,
+        }
+    }
+
+    // A stream that must be exactly len bytes long, e.g. the whole target
+    pub fn with_len(config: &Config, start: ZBlockId, len: usize) -> Self {
+        BlockChunks {
+            expected_len: Some(len),
+            ..BlockChunks::new(config, start)
+        }
+    }
+
+    pub fn unfilled(&mut self) -> &mut [u8] {
+        &mut self.buf[self.filled..]
+    }
+
+    pub fn fill(&mut self, n: usize) -> Result<bool> {
+        self.filled += n;
+        self.read += n;
+        if n == 0 {
+            self.eof = true;
+        }
+        if let Some(expected) = self.expected_len {
+            if self.read > expected || (self.eof && self.read < expected) {
+                Err(Error::InvalidBodyLength {
+                    length: self.read,
+                    expected,
+                })?;
+            }
+        }
+        Ok(self.eof || self.filled == self.buf.len())
+    }
+
+    // The ready chunk and the block it starts at, unless it is empty
+    pub fn chunk(&self) -> Option<(ZBlockId, &[u8])> {
+        if self.filled > 0 {
+            Some((self.start, &self.buf[..self.filled]))
+        } else {
+            None
+        }
+    }
+
+    pub fn advance(&mut self) -> bool {
+        if self.eof {
+            return false;
+        }
+        self.start += self.filled / self.blocksize;
+        self.filled = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_runs() {
+        let data = [1; 64];
+        let events = vec![
+            Event::Write { offset: 32, data: &data[16..32] },
+            Event::Write { offset: 0, data: &data[..16] },
+            Event::NeedRanges(vec![]),
+            Event::Write { offset: 16, data: &data[32..48] },
+            Event::Write { offset: 64, data: &data[..16] },
+        ];
+        let runs = plan_writes(&events);
+        assert!(runs.iter().map(|r| (r.offset, r.len)).collect::<Vec<_>>() == vec![(0, 48), (64, 16)]);
+        assert!(!runs[0].is_contiguous() && runs[1].is_contiguous());
+
+        let mut buf = Vec::new();
+        assert!(runs[0].data(&mut buf) == &[1; 48][..]);
+        assert!(runs[1].data(&mut buf).as_ptr() == data.as_ptr());
+    }
+
+    #[test]
+    fn seed_chunks() {
+        let config = Config::new(1, 5, 16).unwrap();
+        let seed: Vec<u8> = (0..100).collect();
+        let mut chunks = SeedChunks::new(&config, 40);
+        let mut scanned = Vec::new();
+        let mut read = 0;
+        loop {
+            let buf = chunks.unfilled();
+            let n = std::cmp::min(std::cmp::min(buf.len(), 7), seed.len() - read);
+            buf[..n].copy_from_slice(&seed[read..read + n]);
+            read += n;
+            if chunks.fill(n) {
+                if let Some(chunk) = chunks.chunk() {
+                    let (data, offset) = (chunk.data, chunk.offset);
+                    assert!(data[..chunk.seed_len] == seed[offset..offset + chunk.seed_len]);
+                    assert!(data[chunk.seed_len..].iter().all(|&x| x == 0));
+                    scanned.push(offset..offset + data.len());
+                }
+                if !chunks.advance() {
+                    break;
+                }
+            }
+        }
+        // Consecutive chunks overlap by one window, and the last is padded with a block
+        assert!(scanned == vec![0..40, 24..64, 48..88, 72..116]);
+
+        // An empty seed gets no padding
+        let mut chunks = SeedChunks::new(&config, 40);
+        assert!(chunks.fill(0));
+        assert!(chunks.chunk().is_none());
+        assert!(!chunks.advance());
+
+        assert!(padded_tail(&config, &seed) == [&seed[84..], &[0; 16][..]].concat());
+        assert!(padded_tail(&config, &seed[..5]) == [&seed[..5], &[0; 16][..]].concat());
+        assert!(padded_tail(&config, &[]).is_empty());
+    }
+
+    #[test]
+    fn block_chunks_len() {
+        let config = Config::new(1, 5, 16).unwrap();
+
+        let mut chunks = BlockChunks::with_len(&config, 0, 40);
+        assert!(!chunks.fill(40).unwrap());
+        assert!(chunks.fill(0).unwrap());
+
+        // Past the end of a short last block, or short of it
+        let mut chunks = BlockChunks::with_len(&config, 0, 40);
+        assert!(chunks.fill(41).is_err());
+        let mut chunks = BlockChunks::with_len(&config, 0, 40);
+        assert!(!chunks.fill(39).unwrap());
+        assert!(chunks.fill(0).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 extern crate test;
 use std::collections::HashMap;
 use std::hash::BuildHasherDefault;
+use std::marker::PhantomData;
 use fnv::*;
 
 use crate::error::*;
@@ -8,75 +9,218 @@ use super::types::*;
 
 pub type ZBlockId = usize;
 
+// Block IDs are stored as u32 internally; these mark the end of a chain and a block that isn't
+// in the map.
+const CHAIN_END: u32 = u32::MAX;
+const UNLINKED: u32 = u32::MAX - 1;
+
 // Too much for zblock.
 #[derive(Copy, Clone, Debug, Default)]
-pub struct ZBlock {
+pub struct ZBlock<H: StrongHash = MD4Digest> {
     pub rsum: Rsum,
-    pub checksum: PartialChecksum,
+    pub checksum: PartialChecksum<H>,
 }
 
-pub type ChecksumMap = HashMap<PartialChecksum, Vec<ZBlockId>, BuildHasherDefault<FnvHasher>>;
-
+// Blocks sharing an rsum are kept in a doubly linked chain threaded through `next` and `prev`,
+// so each block costs its rsum, its truncated checksum and two links, plus a hash entry for the
+// first block of a chain. The head's `prev` points at the tail, so blocks can be appended and
+// removed in constant time however long the chain gets.
 #[derive(Clone)]
-pub struct ZBlockMap {
-    pub rsum_map: HashMap<Rsum, ChecksumMap, BuildHasherDefault<FnvHasher>>,
-    pub blocklist: Vec<ZBlock>,
+pub struct ZBlockMap<H: StrongHash = MD4Digest> {
+    heads: HashMap<Rsum, u32, BuildHasherDefault<FnvHasher>>,
+    next: Vec<u32>,
+    prev: Vec<u32>,
+    rsums: Vec<Rsum>,
+    checksums: Vec<u8>, // checksum_bytes per block
+    checksum_bytes: usize,
+    hash: PhantomData<H>,
 }
 
-impl ZBlockMap {
-    pub fn new(num_blocks: usize) -> Self {
-        ZBlockMap {
-            rsum_map: HashMap::default(),
-            blocklist: vec![ZBlock::default(); num_blocks],
+impl<H: StrongHash> ZBlockMap<H> {
+    pub fn new(num_blocks: usize, checksum_bytes: usize) -> Result<Self> {
+        if num_blocks >= UNLINKED as usize {
+            Err(Error::InvalidConfig {
+                reason: "too many blocks",
+            })?;
         }
+        if checksum_bytes == 0 || checksum_bytes > H::LENGTH {
+            Err(Error::InvalidChecksumLength {
+                length: checksum_bytes,
+                max: H::LENGTH,
+            })?;
+        }
+        let checksums_len = match num_blocks.checked_mul(checksum_bytes) {
+            Some(len) => len,
+            None => Err(Error::InvalidConfig {
+                reason: "too many blocks",
+            })?,
+        };
+        Ok(ZBlockMap {
+            heads: HashMap::default(),
+            next: vec![UNLINKED; num_blocks],
+            prev: vec![UNLINKED; num_blocks],
+            rsums: vec![Rsum::default(); num_blocks],
+            checksums: vec![0; checksums_len],
+            checksum_bytes,
+            hash: PhantomData,
+        })
+    }
+
+    // Blocks with this rsum, or None on a weak miss
+    pub fn search_weak(&self, rsum: Rsum) -> Option<Candidates<'_, H>> {
+        self.heads.get(&rsum).map(|&head| Candidates { map: self, head })
     }
 
-    pub fn search_weak(&self, rsum: Rsum) -> Option<&ChecksumMap> {
-        self.rsum_map.get(&rsum)
+    // The rsum of a block, or None if there is no such block
+    pub fn rsum(&self, block_id: ZBlockId) -> Option<Rsum> {
+        self.rsums.get(block_id).cloned()
     }
 
-    pub fn insert(&mut self, block_id: ZBlockId, block: ZBlock) {
-        assert!(block_id < self.blocklist.len());
-        self.blocklist[block_id] = block;
+    // The stored (truncated) checksum of a block, or None if there is no such block
+    pub fn checksum(&self, block_id: ZBlockId) -> Option<&[u8]> {
+        if block_id >= self.next.len() {
+            return None;
+        }
+        let start = block_id.checked_mul(self.checksum_bytes)?;
+        self.checksums.get(start..start.checked_add(self.checksum_bytes)?)
+    }
 
-        let checksum_map = self.rsum_map.entry(block.rsum).or_insert(HashMap::default());
-        let block_list = checksum_map.entry(block.checksum).or_insert(Vec::new());
-        block_list.push(block_id);
+    pub fn checksum_matches(&self, block_id: ZBlockId, checksum: &PartialChecksum<H>) -> bool {
+        match self.checksum(block_id) {
+            Some(stored) => Some(stored) == checksum.value.as_bytes().get(..self.checksum_bytes),
+            None => false,
+        }
+    }
+
+    pub fn insert(&mut self, block_id: ZBlockId, block: ZBlock<H>) -> Result<()> {
+        if block_id >= self.next.len() {
+            Err(Error::BlockOutOfRange {
+                block_id,
+                num_blocks: self.next.len(),
+            })?;
+        }
+        if self.next[block_id] != UNLINKED {
+            self.remove_block(block_id);
+        }
+
+        self.rsums[block_id] = block.rsum;
+        let checksum_bytes = self.checksum_bytes;
+        self.checksums[block_id * checksum_bytes..(block_id + 1) * checksum_bytes]
+            .copy_from_slice(&block.checksum.value.as_bytes()[..checksum_bytes]);
+
+        // Append to the end of the chain so lookups return blocks in insertion order
+        let id = block_id as u32;
+        self.next[block_id] = CHAIN_END;
+        match self.heads.get(&block.rsum) {
+            None => {
+                self.heads.insert(block.rsum, id);
+                self.prev[block_id] = id;
+            }
+            Some(&head) => {
+                let tail = self.prev[head as usize];
+                self.next[tail as usize] = id;
+                self.prev[block_id] = tail;
+                self.prev[head as usize] = id;
+            }
+        }
+        Ok(())
     }
 
+    // Unlink a block so lookups no longer return it. Unknown blocks are ignored.
     pub fn remove_block(&mut self, block_id: ZBlockId) {
-        let block = self.blocklist[block_id];
-        let checksum_map = self.rsum_map.get_mut(&block.rsum).unwrap();
-        let block_list = checksum_map.get_mut(&block.checksum).unwrap();
-        block_list.remove_item(&block_id);
+        match self.next.get(block_id) {
+            Some(&next) if next != UNLINKED => (),
+            _ => return,
+        }
 
-        if block_list.is_empty() {
-            checksum_map.remove(&block.checksum);
+        let rsum = self.rsums[block_id];
+        let head = match self.heads.get(&rsum) {
+            Some(&head) => head,
+            None => return,
+        };
+        let following = self.next[block_id];
+        let preceding = self.prev[block_id];
+        if head as usize == block_id {
+            if following == CHAIN_END {
+                self.heads.remove(&rsum);
+            } else {
+                // The new head inherits the link to the tail
+                self.heads.insert(rsum, following);
+                self.prev[following as usize] = preceding;
+            }
+        } else {
+            self.next[preceding as usize] = following;
+            if following == CHAIN_END {
+                self.prev[head as usize] = preceding;
+            } else {
+                self.prev[following as usize] = preceding;
+            }
         }
+        self.next[block_id] = UNLINKED;
+        self.prev[block_id] = UNLINKED;
+    }
 
-        if checksum_map.is_empty() {
-            self.rsum_map.remove(&block.rsum);
+    pub fn remove_checksum(&mut self, rsum: Rsum, checksum: PartialChecksum<H>) {
+        let blocks = self.search_weak(rsum).and_then(|c| c.get(&checksum)).unwrap_or_default();
+        for block_id in blocks {
+            self.remove_block(block_id);
         }
     }
 
-    pub fn remove_checksum(&mut self, rsum: Rsum, checksum: PartialChecksum) {
-        let checksum_map = self.rsum_map.get_mut(&rsum).unwrap();
-        checksum_map.remove(&checksum);
+    // Bytes of heap used by the map
+    pub fn heap_size(&self) -> usize {
+        // hashbrown stores one control byte per bucket next to each (key, value) pair
+        self.heads.capacity() * (std::mem::size_of::<(Rsum, u32)>() + 1)
+            + self.next.capacity() * std::mem::size_of::<u32>()
+            + self.prev.capacity() * std::mem::size_of::<u32>()
+            + self.rsums.capacity() * std::mem::size_of::<Rsum>()
+            + self.checksums.capacity()
+    }
+}
+
+// The chain of blocks sharing an rsum
+#[derive(Copy, Clone)]
+pub struct Candidates<'a, H: StrongHash = MD4Digest> {
+    map: &'a ZBlockMap<H>,
+    head: u32,
+}
+
+impl<'a, H: StrongHash> Candidates<'a, H> {
+    // Blocks in the chain whose checksum matches, or None on a strong miss
+    pub fn get(&self, checksum: &PartialChecksum<H>) -> Option<Vec<ZBlockId>> {
+        let map = self.map;
+        let blocks: Vec<ZBlockId> = self.filter(|&id| map.checksum_matches(id, checksum)).collect();
+        if blocks.is_empty() {
+            None
+        } else {
+            Some(blocks)
+        }
+    }
+}
+
+impl<'a, H: StrongHash> Iterator for Candidates<'a, H> {
+    type Item = ZBlockId;
 
-        if checksum_map.is_empty() {
-            self.rsum_map.remove(&rsum);
+    fn next(&mut self) -> Option<ZBlockId> {
+        if self.head == CHAIN_END {
+            return None;
         }
+        let id = self.head;
+        self.head = self.map.next[id as usize];
+        Some(id as usize)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::hash::{Hash, Hasher};
     use test::Bencher;
+    use crate::rcksum::test_util::random_data;
 
     #[test]
     fn sanity() {
-        let mut map = ZBlockMap::new(10);
+        let mut map: ZBlockMap = ZBlockMap::new(10, 5).unwrap();
         map.insert(
             0,
             ZBlock {
@@ -86,7 +230,7 @@ mod tests {
                     length: 5,
                 },
             },
-        );
+        ).unwrap();
         map.insert(
             1,
             ZBlock {
@@ -96,7 +240,7 @@ mod tests {
                     length: 5,
                 },
             },
-        );
+        ).unwrap();
         map.insert(
             2,
             ZBlock {
@@ -106,7 +250,7 @@ mod tests {
                     length: 5,
                 },
             },
-        );
+        ).unwrap();
         map.insert(
             3,
             ZBlock {
@@ -116,30 +260,30 @@ mod tests {
                     length: 5,
                 },
             },
-        );
+        ).unwrap();
 
         let result = map.search_weak(Rsum(1, 2)).unwrap();
-        assert!(result.len() == 2);
+        assert!(result.count() == 3);
         assert!(
             result.get(&PartialChecksum {
                 value: [1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0].into(),
                 length: 5
-            }) == Some(&vec![0, 3])
+            }) == Some(vec![0, 3])
         );
         assert!(
             result.get(&PartialChecksum {
                 value: [2, 2, 2, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0].into(),
                 length: 5
-            }) == Some(&vec![1])
+            }) == Some(vec![1])
         );
 
         let result = map.search_weak(Rsum(3, 2)).unwrap();
-        assert!(result.len() == 1);
+        assert!(result.count() == 1);
         assert!(
             result.get(&PartialChecksum {
                 value: [3, 3, 3, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0].into(),
                 length: 5
-            }) == Some(&vec![2])
+            }) == Some(vec![2])
         );
 
         let result = map.search_weak(Rsum(255, 2));
@@ -147,51 +291,267 @@ mod tests {
 
         map.remove_block(0);
         let result = map.search_weak(Rsum(1, 2)).unwrap();
-        assert!(result.len() == 2);
+        assert!(result.count() == 2);
         assert!(
             result.get(&PartialChecksum {
                 value: [1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0].into(),
                 length: 5
-            }) == Some(&vec![3])
+            }) == Some(vec![3])
         );
         assert!(
             result.get(&PartialChecksum {
                 value: [2, 2, 2, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0].into(),
                 length: 5
-            }) == Some(&vec![1])
+            }) == Some(vec![1])
         );
 
         map.remove_block(1);
         let result = map.search_weak(Rsum(1, 2)).unwrap();
-        assert!(result.len() == 1);
+        assert!(result.count() == 1);
         assert!(
 				result.get(&PartialChecksum {
                 value: [1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0].into(),
                 length: 5
-            }) == Some(&vec![3])
+            }) == Some(vec![3])
         );
 
         map.remove_block(3);
         assert!(map.search_weak(Rsum(1, 2)).is_none());
 
         let result = map.search_weak(Rsum(3, 2)).unwrap();
-        assert!(result.len() == 1);
+        assert!(result.count() == 1);
         assert!(
             result.get(&PartialChecksum {
                 value: [3, 3, 3, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0].into(),
                 length: 5
-            }) == Some(&vec![2])
+            }) == Some(vec![2])
         );
 
         map.remove_block(2);
         assert!(map.search_weak(Rsum(3, 2)).is_none());
+
+        // Bad block IDs are errors, not panics
+        assert!(map.insert(10, ZBlock::default()).is_err());
+        map.remove_block(10);
+        assert!(ZBlockMap::<MD4Digest>::new(1, 17).is_err());
+        assert!(ZBlockMap::<MD4Digest>::new(1, 0).is_err());
+
+        // Blocks past the end have no checksum, however large the ID
+        assert!(map.checksum(9).is_some());
+        assert!(map.checksum(10).is_none());
+        assert!(map.checksum(usize::MAX / 2).is_none());
+        assert!(map.checksum(usize::MAX).is_none());
+        assert!(map.rsum(usize::MAX).is_none());
+    }
+
+    #[test]
+    fn compact_storage() {
+        let num_blocks = 100_000;
+        let mut map: ZBlockMap = ZBlockMap::new(num_blocks, 3).unwrap();
+        let rsums = random_data(1, num_blocks * 4);
+        for (i, rsum) in rsums.chunks(4).enumerate() {
+            let mut value = [0; 16];
+            value[..4].copy_from_slice(&(i as u32).to_le_bytes());
+            map.insert(
+                i,
+                ZBlock {
+                    rsum: Rsum(u16::from_le_bytes([rsum[0], rsum[1]]), u16::from_le_bytes([rsum[2], rsum[3]])),
+                    checksum: PartialChecksum {
+                        value: value.into(),
+                        length: 3,
+                    },
+                },
+            ).unwrap();
+        }
+
+        // Only the truncated checksum is kept, so a block costs a few dozen bytes at most
+        assert!(map.heap_size() / num_blocks <= 32);
+        assert!(map.checksum(70_000) == Some(&70_000u32.to_le_bytes()[..3]));
+        assert!(map.checksum(num_blocks).is_none());
+    }
+
+    #[test]
+    fn shared_rsum() {
+        // Zero-filled regions give long chains of blocks with one rsum; inserting and removing
+        // them must not walk the chain
+        let num_blocks = 200_000;
+        let mut map: ZBlockMap = ZBlockMap::new(num_blocks, 4).unwrap();
+        let zeros = ZBlock {
+            rsum: Rsum::calculate(&[0; 16]),
+            checksum: PartialChecksum {
+                value: MD4Digest::calculate(&[0; 16]),
+                length: 4,
+            },
+        };
+        for id in 0..num_blocks {
+            map.insert(id, zeros).unwrap();
+        }
+        let found = map.search_weak(zeros.rsum).and_then(|c| c.get(&zeros.checksum)).unwrap();
+        assert!(found.len() == num_blocks);
+        assert!(found.iter().cloned().eq(0..num_blocks));
+
+        // Remove from the middle, the tail and the head, then append again
+        map.remove_block(num_blocks / 2);
+        map.remove_block(num_blocks - 1);
+        map.remove_block(0);
+        map.insert(0, zeros).unwrap();
+        for id in (1..num_blocks / 2).step_by(2) {
+            map.remove_block(id);
+        }
+
+        let found = map.search_weak(zeros.rsum).unwrap().get(&zeros.checksum).unwrap();
+        let expected: Vec<ZBlockId> = (2..num_blocks / 2).step_by(2)
+            .chain(num_blocks / 2 + 1..num_blocks - 1)
+            .chain(std::iter::once(0))
+            .collect();
+        assert!(found == expected);
+
+        for &id in &expected {
+            map.remove_block(id);
+        }
+        assert!(map.search_weak(zeros.rsum).is_none());
+    }
+
+    #[cfg(feature = "sha256")]
+    #[test]
+    fn sha256_sanity() {
+        let mut map: ZBlockMap<Sha256Digest> = ZBlockMap::new(2, 8).unwrap();
+        map.insert(
+            0,
+            ZBlock {
+                rsum: Rsum::calculate(&[1; 16]),
+                checksum: PartialChecksum {
+                    value: Sha256Digest::calculate(&[1; 16]),
+                    length: 8,
+                },
+            },
+        ).unwrap();
+        map.insert(
+            1,
+            ZBlock {
+                rsum: Rsum::calculate(&[1; 16]),
+                checksum: PartialChecksum {
+                    value: Sha256Digest::calculate(&[2; 16]),
+                    length: 8,
+                },
+            },
+        ).unwrap();
+
+        let result = map.search_weak(Rsum::calculate(&[1; 16])).unwrap();
+        assert!(result.count() == 2);
+        assert!(
+            result.get(&PartialChecksum {
+                value: Sha256Digest::calculate(&[2; 16]),
+                length: 8
+            }) == Some(vec![1])
+        );
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3_sanity() {
+        let mut map: ZBlockMap<Blake3Digest> = ZBlockMap::new(2, 8).unwrap();
+        map.insert(
+            0,
+            ZBlock {
+                rsum: Rsum::calculate(&[1; 16]),
+                checksum: PartialChecksum {
+                    value: Blake3Digest::calculate(&[1; 16]),
+                    length: 8,
+                },
+            },
+        ).unwrap();
+        map.insert(
+            1,
+            ZBlock {
+                rsum: Rsum::calculate(&[1; 16]),
+                checksum: PartialChecksum {
+                    value: Blake3Digest::calculate(&[2; 16]),
+                    length: 8,
+                },
+            },
+        ).unwrap();
+
+        let result = map.search_weak(Rsum::calculate(&[1; 16])).unwrap();
+        assert!(result.count() == 2);
+        assert!(
+            result.get(&PartialChecksum {
+                value: Blake3Digest::calculate(&[2; 16]),
+                length: 8
+            }) == Some(vec![1])
+        );
+    }
+
+    // Blocks of a real file: the test binary itself
+    fn real_blocks() -> Vec<Vec<u8>> {
+        let data = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        data.chunks_exact(1024).map(|c| c.to_vec()).collect()
+    }
+
+    // Fraction of distinct rsums whose hash is shared with another rsum. These always land in
+    // the same bucket, however large the table.
+    fn collision_rate<F: Fn(&Rsum) -> u64>(rsums: &[Rsum], hash: F) -> f64 {
+        let mut distinct = rsums.to_vec();
+        distinct.sort_by_key(|r| (r.0, r.1));
+        distinct.dedup();
+        let mut hashes: Vec<u64> = distinct.iter().map(hash).collect();
+        hashes.sort();
+        hashes.dedup();
+        (distinct.len() - hashes.len()) as f64 / distinct.len() as f64
+    }
+
+    #[test]
+    fn rsum_collisions() {
+        let rsums: Vec<Rsum> = real_blocks().iter().map(|b| Rsum::calculate(b)).collect();
+        // What the Hash impl used to feed the hasher
+        let old = collision_rate(&rsums, |r| {
+            let mut hasher = FnvHasher::default();
+            hasher.write_u16(r.0.wrapping_add(r.1));
+            hasher.finish()
+        });
+        let new = collision_rate(&rsums, |r| {
+            let mut hasher = FnvHasher::default();
+            r.hash(&mut hasher);
+            hasher.finish()
+        });
+        println!("{} blocks, collision rate: old {:.3}, new {:.3}", rsums.len(), old, new);
+        assert!(new < old);
+    }
+
+    #[bench]
+    fn bench_real_data(b: &mut Bencher) {
+        let blocks = real_blocks();
+        let mut map: ZBlockMap = ZBlockMap::new(blocks.len(), 5).unwrap();
+        for (i, block) in blocks.iter().enumerate() {
+            map.insert(
+                i,
+                ZBlock {
+                    rsum: Rsum::calculate(block),
+                    checksum: PartialChecksum {
+                        value: MD4Digest::calculate(block),
+                        length: 5,
+                    },
+                },
+            ).unwrap();
+        }
+        // Look up every block, plus the same number of misses from blocks shifted by a byte
+        let mut lookups: Vec<Rsum> = blocks.iter().map(|b| Rsum::calculate(b)).collect();
+        lookups.extend(blocks.windows(2).map(|w| {
+            let mut shifted = w[0][1..].to_vec();
+            shifted.push(w[1][0]);
+            Rsum::calculate(&shifted)
+        }));
+
+        b.iter(|| {
+            lookups.iter().filter(|&&r| map.search_weak(r).is_some()).count()
+        });
     }
 
     #[bench]
     fn bench(b: &mut Bencher) {
-        let mut map = ZBlockMap::new(200);
+        let mut map: ZBlockMap = ZBlockMap::new(200, 5).unwrap();
 
-        let my_rsums = vec![
+        let my_rsums = [
             Rsum(1, 2),
             Rsum(2, 3),
             Rsum(3, 4),
@@ -212,7 +572,7 @@ mod tests {
                         length: 5,
                     },
                 },
-            );
+            ).unwrap();
         }
 
         b.iter( || {
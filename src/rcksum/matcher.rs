@@ -0,0 +1,852 @@
+extern crate test;
+use std::ops::Range;
+use fnv::{FnvHashMap, FnvHashSet};
+use crate::error::*;
+use super::types::*;
+use super::map::*;
+use super::data_window::*;
+
+// Longest checksum_bytes any StrongHash can support
+pub const MAX_CHECKSUM_BYTES: usize = 32;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    pub(crate) seq_matches: usize, // 1 or 2
+    pub(crate) checksum_bytes: usize,
+    pub(crate) blocksize: usize,
+}
+
+impl Config {
+    pub fn new(seq_matches: usize, checksum_bytes: usize, blocksize: usize) -> Result<Self> {
+        if !(1..=2).contains(&seq_matches) {
+            Err(Error::InvalidConfig {
+                reason: "seq_matches must be 1 or 2",
+            })?;
+        }
+        if blocksize == 0 {
+            Err(Error::InvalidConfig {
+                reason: "blocksize must be non-zero",
+            })?;
+        }
+        if checksum_bytes == 0 || checksum_bytes > MAX_CHECKSUM_BYTES {
+            Err(Error::InvalidChecksumLength {
+                length: checksum_bytes,
+                max: MAX_CHECKSUM_BYTES,
+            })?;
+        }
+        Ok(Config {
+            seq_matches,
+            checksum_bytes,
+            blocksize,
+        })
+    }
+
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    // Pick parameters for a target of this length the way zsyncmake does: larger blocks for
+    // large files, and enough checksum bytes that a false block match is unlikely anywhere in
+    // the file.
+    pub fn for_file_len(len: usize) -> Result<Self> {
+        let blocksize = if len < 100_000_000 { 2048 } else { 4096 };
+        let seq_matches = if len > blocksize { 2 } else { 1 };
+
+        let len = std::cmp::max(len, 1) as f64;
+        let blocks = (1.0 + (len / blocksize as f64).floor()).log2();
+        let checksum_bytes = ((20.0 + len.log2() + blocks) / seq_matches as f64 / 8.0).ceil() as usize;
+        // Each block also needs enough bytes to be identified on its own
+        let checksum_bytes = std::cmp::max(checksum_bytes, ((7.9 + 20.0 + blocks) / 8.0) as usize);
+
+        Config::new(seq_matches, std::cmp::min(checksum_bytes, MD4Digest::LENGTH), blocksize)
+    }
+
+    pub fn seq_matches(&self) -> usize {
+        self.seq_matches
+    }
+
+    pub fn checksum_bytes(&self) -> usize {
+        self.checksum_bytes
+    }
+
+    pub fn blocksize(&self) -> usize {
+        self.blocksize
+    }
+}
+
+// Builds a Config, starting from zsync's defaults for small files. build() validates the
+// result like Config::new.
+#[derive(Copy, Clone, Debug)]
+pub struct ConfigBuilder {
+    seq_matches: usize,
+    checksum_bytes: usize,
+    blocksize: usize,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        ConfigBuilder {
+            seq_matches: 1,
+            checksum_bytes: MD4Digest::LENGTH,
+            blocksize: 2048,
+        }
+    }
+}
+
+impl ConfigBuilder {
+    pub fn seq_matches(mut self, seq_matches: usize) -> Self {
+        self.seq_matches = seq_matches;
+        self
+    }
+
+    pub fn checksum_bytes(mut self, checksum_bytes: usize) -> Self {
+        self.checksum_bytes = checksum_bytes;
+        self
+    }
+
+    pub fn blocksize(mut self, blocksize: usize) -> Self {
+        self.blocksize = blocksize;
+        self
+    }
+
+    pub fn build(self) -> Result<Config> {
+        Config::new(self.seq_matches, self.checksum_bytes, self.blocksize)
+    }
+}
+
+// Output of the matcher; the caller is responsible for carrying these out.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event<'a> {
+    // Write these bytes at this offset of the output
+    Write { offset: usize, data: &'a [u8] },
+    // These block ranges are still missing and must be fetched remotely
+    NeedRanges(Vec<Range<ZBlockId>>),
+}
+
+// The block matching state machine. It never touches a file or socket: data goes in through
+// the submit_* methods, and what should happen to the output comes back out as Events. Blocks
+// only count as known once the driver has carried out their writes and passed the events
+// back to commit.
+pub struct Matcher<H: StrongHash = MD4Digest> {
+    config: Config,
+    num_blocks: usize,
+    target_len: usize, // The last block may be short
+    blockmap: ZBlockMap<H>,
+    known_blocks: Vec<bool>,
+    missing: usize, // Blocks not yet known
+    failures: FnvHashMap<ZBlockId, usize>, // Remote blocks that failed verification
+    max_failures: Option<usize>,
+}
+
+impl<H: StrongHash> Matcher<H> {
+    pub fn new(config: Config, num_blocks: usize) -> Result<Self> {
+        // The config doesn't know which hash it will be used with
+        if config.checksum_bytes == 0 || config.checksum_bytes > H::LENGTH {
+            Err(Error::InvalidChecksumLength {
+                length: config.checksum_bytes,
+                max: H::LENGTH,
+            })?;
+        }
+
+        Ok(Matcher {
+            config,
+            num_blocks,
+            target_len: num_blocks.checked_mul(config.blocksize).ok_or(Error::InvalidConfig {
+                reason: "target too large",
+            })?,
+            blockmap: ZBlockMap::new(num_blocks, config.checksum_bytes)?,
+            known_blocks: vec![false; num_blocks],
+            missing: num_blocks,
+            failures: FnvHashMap::default(),
+            max_failures: None,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    // Set the exact length of the target, if it isn't a whole number of blocks. Writes are
+    // clipped to it, and remote data for the last block must end there.
+    pub fn set_target_len(&mut self, target_len: usize) -> Result<()> {
+        if target_len.div_ceil(self.config.blocksize) != self.num_blocks {
+            Err(Error::InvalidConfig {
+                reason: "target length doesn't match the number of blocks",
+            })?;
+        }
+        self.target_len = target_len;
+        Ok(())
+    }
+
+    pub fn target_len(&self) -> usize {
+        self.target_len
+    }
+
+    // Length of a block; only the last block can be short
+    fn block_len(&self, id: ZBlockId) -> usize {
+        std::cmp::min(self.config.blocksize, self.target_len - id * self.config.blocksize)
+    }
+
+    // Register the checksums of a block of the target file
+    pub fn insert_block(&mut self, id: ZBlockId, block: ZBlock<H>) -> Result<()> {
+        self.blockmap.insert(id, block)
+    }
+
+    // Blocks outside the target are never known
+    pub fn is_block_known(&self, id: ZBlockId) -> bool {
+        self.known_blocks.get(id).cloned().unwrap_or(false)
+    }
+
+    // How many times remote data for this block has failed verification
+    pub fn failure_count(&self, id: ZBlockId) -> usize {
+        self.failures.get(&id).cloned().unwrap_or(0)
+    }
+
+    // Give up on the transfer once remote data for a block has failed verification this many
+    // times. By default there is no limit.
+    pub fn set_max_failures(&mut self, max_failures: usize) -> Result<()> {
+        if max_failures == 0 {
+            Err(Error::InvalidConfig {
+                reason: "max_failures must be non-zero",
+            })?;
+        }
+        self.max_failures = Some(max_failures);
+        Ok(())
+    }
+
+    // TooManyFailures naming every one of these blocks that has reached the limit, if any has
+    pub fn check_failures(&self, failed: &[ZBlockId]) -> Result<()> {
+        let max = match self.max_failures {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let mut ranges: Vec<Range<ZBlockId>> = Vec::new();
+        for &id in failed.iter().filter(|&&id| self.failure_count(id) >= max) {
+            match ranges.last_mut() {
+                Some(last) if last.end == id => last.end = id + 1,
+                _ => ranges.push(id..id + 1),
+            }
+        }
+        if !ranges.is_empty() {
+            Err(Error::TooManyFailures { ranges, failures: max })?;
+        }
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing == 0
+    }
+
+    // Collapse the blocks we don't have yet into ranges
+    pub fn needed_ranges(&self) -> Vec<Range<ZBlockId>> {
+        let mut ranges: Vec<Range<ZBlockId>> = Vec::new();
+        for (id, _) in self.known_blocks.iter().enumerate().filter(|(_, &k)| !k) {
+            match ranges.last_mut() {
+                Some(last) if last.end == id => last.end = id + 1,
+                _ => ranges.push(id..id + 1),
+            }
+        }
+        ranges
+    }
+
+    // Returns a NeedRanges event, or None once every block is known
+    pub fn needed(&self) -> Option<Event<'static>> {
+        let ranges = self.needed_ranges();
+        if ranges.is_empty() {
+            None
+        } else {
+            Some(Event::NeedRanges(ranges))
+        }
+    }
+
+    // Record that the Write events have been carried out, so their blocks are known and no
+    // longer searched for. Writes that failed must not be committed; their blocks stay needed.
+    pub fn commit(&mut self, events: &[Event]) {
+        for event in events {
+            if let Event::Write { offset, .. } = event {
+                let id = offset / self.config.blocksize;
+                if id < self.num_blocks && !self.known_blocks[id] {
+                    self.known_blocks[id] = true;
+                    self.missing -= 1;
+                    self.blockmap.remove_block(id);
+                }
+            }
+        }
+    }
+
+    // Emit writes for the blocks that are neither known nor already in this batch
+    fn accept_blocks<'a>(&self, blocks: &[ZBlockId], data: &'a [u8], emitted: &mut FnvHashSet<ZBlockId>, events: &mut Vec<Event<'a>>) {
+        debug_assert!(data.len() <= self.config.blocksize);
+        for &b in blocks {
+            if self.known_blocks[b] || !emitted.insert(b) {
+                continue;
+            }
+            // Calculate offset into the file from the block ID. Seed matches for a short last
+            // block include its zero padding, which isn't part of the target.
+            events.push(Event::Write {
+                offset: b * self.config.blocksize,
+                data: &data[..std::cmp::min(data.len(), self.block_len(b))],
+            });
+        }
+    }
+
+    fn check_block_match(&self, rsum: Rsum, data: &[u8]) -> Option<Vec<ZBlockId>> {
+        // Look up the rsum in the blockmap
+        let candidates = self.blockmap.search_weak(rsum)?;
+
+        // Weak hash hit; calculate the strong hash of this block
+        let checksum = PartialChecksum {
+            value: H::calculate(data),
+            length: self.config.checksum_bytes,
+        };
+
+        // Look up the strong hash in the blockmap
+        candidates.get(&checksum)
+    }
+
+    // Find the target blocks present in data. This only reads the block map, so several
+    // segments of a seed can be scanned at once.
+    fn scan<'a>(&self, data: &'a [u8]) -> Vec<(Vec<ZBlockId>, &'a [u8])> {
+        self.scan_with(data, Rsum::calculate)
+    }
+
+    // With seq_matches 2, blocks only match in pairs: a block matches where the one after it
+    // or the one before it matches too, and a run carries on while each block follows on from
+    // the last. Pairing with the block before also finds blocks whose neighbour is already
+    // known, or whose successor lies past the end of the data, e.g. at a chunk boundary. This
+    // returns the candidates for the block at the start of the window; run holds the (sorted)
+    // blocks matched by the data just before it.
+    fn check_seq_match(&self, rsums: &[Rsum; 2], cur: &[u8], next: Option<&[u8]>, prev: Option<&[u8]>, run: &[ZBlockId]) -> Option<Vec<ZBlockId>> {
+        let blocks = self.check_block_match(rsums[0], cur)?;
+
+        let continued: Vec<ZBlockId> = blocks.iter().cloned()
+            .filter(|&id| id > 0 && run.binary_search(&(id - 1)).is_ok())
+            .collect();
+        if !continued.is_empty() {
+            return Some(continued);
+        }
+
+        // Pair up with the next or the previous block, hashing each at most once
+        let mut next_sums = None;
+        let mut prev_sums = None;
+        let paired: Vec<ZBlockId> = blocks.into_iter().filter(|&id| {
+            if let Some(next) = next {
+                if id + 1 < self.num_blocks && self.blockmap.rsum(id + 1) == Some(rsums[1]) {
+                    let (_, checksum) = next_sums.get_or_insert_with(|| self.block_sums(next));
+                    if self.blockmap.checksum_matches(id + 1, checksum) {
+                        return true;
+                    }
+                }
+            }
+            match prev {
+                Some(prev) if id > 0 => {
+                    let (rsum, checksum) = prev_sums.get_or_insert_with(|| self.block_sums(prev));
+                    self.blockmap.rsum(id - 1) == Some(*rsum) && self.blockmap.checksum_matches(id - 1, checksum)
+                }
+                _ => false,
+            }
+        }).collect();
+
+        if paired.is_empty() {
+            None
+        } else {
+            Some(paired)
+        }
+    }
+
+    fn block_sums(&self, data: &[u8]) -> (Rsum, PartialChecksum<H>) {
+        let checksum = PartialChecksum {
+            value: H::calculate(data),
+            length: self.config.checksum_bytes,
+        };
+        (Rsum::calculate(data), checksum)
+    }
+
+    // scan, with the function used to compute rsums from scratch after a match
+    fn scan_with<'a>(&self, data: &'a [u8], calculate: fn(&[u8]) -> Rsum) -> Vec<(Vec<ZBlockId>, &'a [u8])> {
+        let mut matches = Vec::new();
+        let seq = self.config.seq_matches > 1;
+
+        // Data shorter than the window can't contain a match. A block can pair with the one
+        // before it, so the window itself only has to be one block long.
+        if data.len() < self.config.blocksize * self.config.seq_matches {
+            return matches;
+        }
+        let limit = data.len() - self.config.blocksize;
+
+        // Create a DataWindow to view the data
+        let mut data = match DataWindow::new(self.config.blocksize, limit, data) {
+            Ok(data) => data,
+            Err(_) => return matches,
+        };
+        let mut rsums = [Rsum::default(), Rsum::default()];
+
+        rsums[0] = calculate(data.get_cur_block());
+        if seq {
+            rsums[1] = match data.get_nth_block(1) {
+                Ok(block) => calculate(block),
+                Err(_) => return matches,
+            };
+        }
+        let mut run: Vec<ZBlockId> = Vec::new();
+        let mut rolled: usize = 0;
+
+        // Search through until we get a block hit
+        loop {
+            let next = if seq { data.get_nth_block(1).ok() } else { None };
+            let blocks_found = if seq {
+                self.check_seq_match(&rsums, data.get_cur_block(), next, data.get_prev_block().ok(), &run)
+            } else {
+                self.check_block_match(rsums[0], data.get_cur_block())
+            };
+
+            if let Some(b) = blocks_found {
+                matches.push((b.clone(), data.get_cur_block()));
+                if seq {
+                    run = b;
+                    run.sort_unstable();
+                }
+
+                if data.advance_n_blocks(1).is_err() {
+                    break;
+                }
+
+                rsums[0] = if next.is_some() {
+                    rsums[1]
+                } else {
+                    calculate(data.get_cur_block())
+                };
+
+                // Past the last full pair only the previous block can pair up, which doesn't
+                // need rsums[1]
+                if seq {
+                    if let Ok(block) = data.get_nth_block(1) {
+                        rsums[1] = calculate(block);
+                    }
+                }
+            } else {
+                run.clear();
+
+                // We didn't match any data, advance the window by one byte and update the
+                // rolling checksum.
+                let oc = data.get_cur_block()[0];
+                if data.advance_byte().is_err() {
+                    break;
+                }
+
+                let nc = {
+                    let new_block = data.get_cur_block();
+                    new_block[new_block.len() - 1]
+                };
+                rsums[0].update(oc, nc, self.config.blocksize);
+                if seq {
+                    if let Ok(next_block) = data.get_nth_block(1) {
+                        rsums[1].update(nc, next_block[next_block.len() - 1], self.config.blocksize);
+                    }
+                }
+
+                // Checking every position would make debug scans O(n * blocksize)
+                rolled += 1;
+                debug_assert!(!rolled.is_multiple_of(self.config.blocksize) || rsums[0] == Rsum::calculate_scalar(data.get_cur_block()));
+            }
+        }
+
+        matches
+    }
+
+    // Local -> Output
+    pub fn submit_source_data<'a>(&mut self, data: &'a [u8]) -> Result<Vec<Event<'a>>> {
+        let mut events = Vec::new();
+        let mut emitted = FnvHashSet::default();
+        for (blocks, block_data) in self.scan(data) {
+            self.accept_blocks(&blocks, block_data, &mut emitted, &mut events);
+        }
+        Ok(events)
+    }
+
+    // Local -> Output, splitting data into overlapping segments scanned on separate threads.
+    // Blocks found by more than one segment are only written once.
+    pub fn submit_source_data_parallel<'a>(&mut self, data: &'a [u8], threads: usize) -> Result<Vec<Event<'a>>> {
+        let window = self.config.blocksize * self.config.seq_matches;
+        // Number of positions a block can start at
+        let positions = (data.len() + 1).saturating_sub(window);
+        let threads = std::cmp::max(1, std::cmp::min(threads, positions));
+        if threads == 1 {
+            return self.submit_source_data(data);
+        }
+
+        // Segment k scans the block starts in [bounds[k], bounds[k+1]), and so needs the data up
+        // to the end of the window starting at the last of them
+        let bounds: Vec<usize> = (0..=threads).map(|k| k * positions / threads).collect();
+        let this = &*self;
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let workers: Vec<_> = bounds.windows(2).map(|b| {
+                let segment = &data[b[0]..b[1] + window - 1];
+                scope.spawn(move || this.scan(segment))
+            }).collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+
+        let mut events = Vec::new();
+        let mut emitted = FnvHashSet::default();
+        for (blocks, block_data) in results.into_iter().flatten() {
+            self.accept_blocks(&blocks, block_data, &mut emitted, &mut events);
+        }
+        Ok(events)
+    }
+
+    // Remote -> Output. The last block of a target may be short; it is checked as if padded
+    // with zeros, the way its checksum was generated, but only data itself is written. Every
+    // other block must be exactly blocksize long.
+    // Blocks we already have are ignored; data that doesn't match the block's checksum is an
+    // error.
+    pub fn submit_remote_block<'a>(&mut self, id: ZBlockId, data: &'a [u8]) -> Result<Vec<Event<'a>>> {
+        let mut events = Vec::new();
+        if id >= self.num_blocks {
+            Err(Error::BlockOutOfRange {
+                block_id: id,
+                num_blocks: self.num_blocks,
+            })?;
+        }
+        if data.len() != self.block_len(id) {
+            Err(Error::InvalidBlockLength {
+                block_id: id,
+                length: data.len(),
+                blocksize: self.config.blocksize,
+            })?;
+        }
+        if self.known_blocks[id] {
+            return Ok(events);
+        }
+
+        let value = if data.len() < self.config.blocksize {
+            let mut padded = data.to_vec();
+            padded.resize(self.config.blocksize, 0);
+            H::calculate(&padded)
+        } else {
+            H::calculate(data)
+        };
+        let checksum = PartialChecksum {
+            value,
+            length: self.config.checksum_bytes,
+        };
+
+        if !self.blockmap.checksum_matches(id, &checksum) {
+            *self.failures.entry(id).or_insert(0) += 1;
+            Err(Error::ChecksumMismatch { block_id: id })?;
+        }
+        self.accept_blocks(&[id], data, &mut FnvHashSet::default(), &mut events);
+
+        Ok(events)
+    }
+
+    // Remote -> Output, for a run of consecutive blocks starting at start. Each block is
+    // verified on its own; the good ones are written and the IDs of the bad ones are returned
+    // so they can be fetched again.
+    pub fn submit_remote_range<'a>(&mut self, start: ZBlockId, data: &'a [u8]) -> Result<(Vec<Event<'a>>, Vec<ZBlockId>)> {
+        let count = data.len().div_ceil(self.config.blocksize);
+        match start.checked_add(count) {
+            Some(end) if end <= self.num_blocks => (),
+            _ => Err(Error::BlockOutOfRange {
+                block_id: start.saturating_add(count.saturating_sub(1)),
+                num_blocks: self.num_blocks,
+            })?,
+        }
+
+        let mut events = Vec::new();
+        let mut failed = Vec::new();
+        for (id, block) in (start..).zip(data.chunks(self.config.blocksize)) {
+            match self.submit_remote_block(id, block) {
+                Ok(mut written) => events.append(&mut written),
+                // A truncated response; the block is refetched but doesn't count as a failure
+                Err(Error::ChecksumMismatch { .. }) | Err(Error::InvalidBlockLength { .. }) => failed.push(id),
+                Err(e) => Err(e)?,
+            }
+        }
+        Ok((events, failed))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+    use crate::rcksum::test_util::*;
+    use test::Bencher;
+
+    #[test]
+    fn events() {
+        let mut matcher = Matcher::new(small_config(), 4).unwrap();
+        insert_small_target(|id, block| matcher.insert_block(id, block));
+        matcher.insert_block(3, block(&[3; 16])).unwrap();
+        assert!(matcher.needed() == Some(Event::NeedRanges(vec![0..4])));
+
+        let mut seed = vec![9, 9];
+        seed.extend_from_slice(&[1; 16]);
+        seed.extend_from_slice(&[0; 16]);
+        let events = matcher.submit_source_data(&seed).unwrap();
+        assert!(
+            events == vec![
+                Event::Write { offset: 0, data: &[1; 16] },
+                Event::Write { offset: 32, data: &[1; 16] },
+            ]
+        );
+
+        // Until the writes are committed the blocks are still needed
+        assert!(matcher.needed_ranges() == vec![0..4]);
+        assert!(matcher.submit_source_data(&seed).unwrap() == events);
+        matcher.commit(&events);
+        assert!(matcher.needed_ranges() == vec![1..2, 3..4]);
+        assert!(matcher.submit_source_data(&seed).unwrap().is_empty());
+
+        // A corrupt remote block is rejected
+        match matcher.submit_remote_block(3, &[4; 16]) {
+            Err(Error::ChecksumMismatch { block_id: 3 }) => (),
+            _ => panic!("corrupt block accepted"),
+        }
+
+        let events = matcher.submit_remote_block(3, &[3; 16]).unwrap();
+        assert!(events == vec![Event::Write { offset: 48, data: &[3; 16] }]);
+        matcher.commit(&events);
+        let events = matcher.submit_remote_block(1, &[2; 16]).unwrap();
+        assert!(events == vec![Event::Write { offset: 16, data: &[2; 16] }]);
+        matcher.commit(&events);
+
+        // Blocks we already have are not written again
+        assert!(matcher.submit_remote_block(1, &[2; 16]).unwrap().is_empty());
+        assert!(matcher.is_complete());
+        assert!(matcher.needed().is_none());
+    }
+
+    #[test]
+    fn remote_range() {
+        let config = small_config();
+        let mut matcher = Matcher::new(config, 4).unwrap();
+        matcher.insert_block(0, block(&[1; 16])).unwrap();
+        matcher.insert_block(1, block(&[2; 16])).unwrap();
+        matcher.insert_block(2, block(&[3; 16])).unwrap();
+        let mut last = vec![4; 8];
+        last.resize(16, 0);
+        matcher.insert_block(3, block(&last)).unwrap();
+        matcher.set_target_len(56).unwrap();
+
+        // Block 2 is corrupt; the last block is short
+        let mut data = vec![2; 16];
+        data.extend_from_slice(&[9; 16]);
+        data.extend_from_slice(&[4; 8]);
+        let (events, failed) = matcher.submit_remote_range(1, &data).unwrap();
+        assert!(
+            events == vec![
+                Event::Write { offset: 16, data: &[2; 16] },
+                Event::Write { offset: 48, data: &[4; 8] },
+            ]
+        );
+        assert!(failed == vec![2]);
+        assert!(matcher.failure_count(2) == 1 && matcher.failure_count(1) == 0);
+        matcher.commit(&events);
+        assert!(matcher.needed_ranges() == vec![0..1, 2..3]);
+
+        // A response cut short in a middle block is refetched, but isn't a checksum failure
+        let (events, failed) = matcher.submit_remote_range(2, &[3; 8]).unwrap();
+        assert!(events.is_empty() && failed == vec![2]);
+        assert!(matcher.failure_count(2) == 1);
+        let (events, _) = matcher.submit_remote_range(0, &[1; 16]).unwrap();
+        matcher.commit(&events);
+
+        // Ranges running past the end of the target are rejected outright
+        assert!(matcher.submit_remote_range(2, &[0; 33]).is_err());
+        assert!(matcher.submit_remote_range(usize::MAX, &[0; 16]).is_err());
+        assert!(matcher.needed_ranges() == vec![2..3]);
+    }
+
+    #[test]
+    fn short_inputs() {
+        let config = small_config();
+        let mut matcher = Matcher::new(config, 2).unwrap();
+        matcher.insert_block(0, block(&[1; 16])).unwrap();
+        matcher.insert_block(1, block(&[2; 16])).unwrap();
+
+        // Empty seeds and seeds shorter than a block
+        assert!(matcher.submit_source_data(&[]).unwrap().is_empty());
+        assert!(matcher.submit_source_data(&[1; 15]).unwrap().is_empty());
+        assert!(matcher.submit_source_data_parallel(&[1; 15], 4).unwrap().is_empty());
+        assert!(matcher.submit_source_data(&[1; 16]).unwrap().len() == 1);
+
+        // Seeds shorter than the window of two blocks
+        let config = Config::new(2, 5, 16).unwrap();
+        let mut matcher = Matcher::new(config, 2).unwrap();
+        matcher.insert_block(0, block(&[1; 16])).unwrap();
+        assert!(matcher.submit_source_data(&[1; 31]).unwrap().is_empty());
+        assert!(matcher.submit_source_data_parallel(&[1; 31], 4).unwrap().is_empty());
+
+        // Empty targets
+        let mut matcher: Matcher = Matcher::new(config, 0).unwrap();
+        assert!(matcher.submit_source_data(&[1; 64]).unwrap().is_empty());
+        assert!(matcher.is_complete());
+        assert!(matcher.needed().is_none());
+
+        // Targets shorter than one block
+        let mut target = vec![7; 10];
+        target.resize(16, 0);
+        let mut matcher = Matcher::new(config, 1).unwrap();
+        matcher.insert_block(0, block(&target)).unwrap();
+        assert!(matcher.set_target_len(17).is_err());
+        matcher.set_target_len(10).unwrap();
+        assert!(matcher.submit_source_data(&[7; 10]).unwrap().is_empty());
+        assert!(matcher.submit_remote_block(0, &[7; 9]).is_err());
+        assert!(matcher.submit_remote_block(0, &target).is_err());
+        match matcher.submit_remote_block(0, &[7; 17]) {
+            Err(Error::InvalidBlockLength { block_id: 0, length: 17, blocksize: 16 }) => (),
+            _ => panic!("overlong block accepted"),
+        }
+        match matcher.submit_remote_block(1, &[7; 10]) {
+            Err(Error::BlockOutOfRange { block_id: 1, num_blocks: 1 }) => (),
+            _ => panic!("block out of range accepted"),
+        }
+        let events = matcher.submit_remote_block(0, &[7; 10]).unwrap();
+        assert!(events == vec![Event::Write { offset: 0, data: &[7; 10] }]);
+        matcher.commit(&events);
+        assert!(matcher.is_complete());
+    }
+
+    #[test]
+    fn checksum_length() {
+        assert!(Config::new(1, 0, 16).is_err());
+        assert!(Config::new(1, 33, 16).is_err());
+        assert!(Config::new(0, 16, 16).is_err());
+        assert!(Config::new(3, 16, 16).is_err());
+        assert!(Config::new(1, 16, 0).is_err());
+        let config = Config::new(1, 16, 1 << 20).unwrap();
+        assert!(Matcher::<MD4Digest>::new(config, usize::MAX / 1024).is_err());
+
+        // Valid in general, but longer than an MD4 digest
+        let config = Config::new(1, 20, 16).unwrap();
+        assert!(Matcher::<MD4Digest>::new(config, 4).is_err());
+        let config = Config::new(1, 16, 16).unwrap();
+        assert!(Matcher::<MD4Digest>::new(config, 4).is_ok());
+    }
+
+    #[test]
+    fn config_builder() {
+        let config = Config::builder().seq_matches(2).checksum_bytes(6).blocksize(4096).build().unwrap();
+        assert!((config.seq_matches(), config.checksum_bytes(), config.blocksize()) == (2, 6, 4096));
+        assert!(Config::builder().seq_matches(3).build().is_err());
+        assert!(Config::builder().blocksize(0).build().is_err());
+
+        // Values zsyncmake picks for the same lengths
+        let config = Config::for_file_len(100).unwrap();
+        assert!((config.seq_matches(), config.checksum_bytes(), config.blocksize()) == (1, 4, 2048));
+        let config = Config::for_file_len(10 << 20).unwrap();
+        assert!((config.seq_matches(), config.checksum_bytes(), config.blocksize()) == (2, 5, 2048));
+        let config = Config::for_file_len(200_000_000).unwrap();
+        assert!((config.seq_matches(), config.checksum_bytes(), config.blocksize()) == (2, 5, 4096));
+        assert!(Config::for_file_len(0).is_ok());
+    }
+
+    #[test]
+    fn parallel_scan() {
+        let config = small_config();
+        let num_blocks = 64;
+        let target = random_target(&config, num_blocks, 3);
+
+        // Shift the seed so that matches don't line up with the segment boundaries
+        let mut seed = vec![0; 5];
+        seed.extend_from_slice(&target);
+        seed[20 * 16 + 9] ^= 0xff;
+
+        let mut sequential: Matcher = Matcher::new(config, num_blocks).unwrap();
+        let mut parallel: Matcher = Matcher::new(config, num_blocks).unwrap();
+        insert_target(&config, &target, |id, block| {
+            sequential.insert_block(id, block)?;
+            parallel.insert_block(id, block)
+        });
+
+        let mut expected = sequential.submit_source_data(&seed).unwrap();
+        let mut events = parallel.submit_source_data_parallel(&seed, 7).unwrap();
+        expected.sort_by_key(|e| match e { Event::Write { offset, .. } => *offset, _ => 0 });
+        events.sort_by_key(|e| match e { Event::Write { offset, .. } => *offset, _ => 0 });
+        assert!(events.len() == num_blocks - 1);
+        assert!(events == expected);
+        parallel.commit(&events);
+        assert!(parallel.needed_ranges() == vec![20..21]);
+    }
+
+    #[test]
+    fn seq_matches() {
+        let config = Config::new(2, 5, 256).unwrap();
+        let blocksize = config.blocksize();
+        let num_blocks = 64;
+        let target = random_target(&config, num_blocks, 7);
+
+        let mut matcher: Matcher = Matcher::new(config, num_blocks).unwrap();
+        insert_target(&config, &target, |id, block| matcher.insert_block(id, block));
+        let block = |id: usize| &target[id * blocksize..(id + 1) * blocksize];
+
+        // A lone block doesn't match without the one after it
+        let mut seed = block(5).to_vec();
+        seed.extend_from_slice(block(9));
+        assert!(matcher.submit_source_data(&seed).unwrap().is_empty());
+        seed.extend_from_slice(block(10));
+        let events = matcher.submit_source_data(&seed).unwrap();
+        assert!(events == vec![
+            Event::Write { offset: 9 * blocksize, data: block(9) },
+            Event::Write { offset: 10 * blocksize, data: block(10) },
+        ]);
+        matcher.commit(&events);
+
+        // A block pairs with the one before it, even if that one is already known and the data
+        // ends with the block
+        let seed = [block(10), block(11)].concat();
+        let events = matcher.submit_source_data(&seed).unwrap();
+        assert!(events == vec![Event::Write { offset: 11 * blocksize, data: &seed[blocksize..] }]);
+        matcher.commit(&events);
+
+        // A corrupt block breaks the run, and the next one starts again
+        let mut seed = vec![0; 3];
+        seed.extend_from_slice(&target);
+        seed[3 + 20 * blocksize + 17] ^= 0xff;
+        let events = matcher.submit_source_data(&seed).unwrap();
+        assert!(events.len() == num_blocks - 4);
+        assert!(matcher.submit_source_data_parallel(&seed, 4).unwrap().len() == num_blocks - 4);
+        matcher.commit(&events);
+        assert!(matcher.needed_ranges() == vec![20..21]);
+
+        // An identical seed completes the target
+        let events = matcher.submit_source_data(&target).unwrap();
+        assert!(events == vec![Event::Write { offset: 20 * blocksize, data: block(20) }]);
+        matcher.commit(&events);
+        assert!(matcher.is_complete());
+    }
+
+    // A seed that matches the target in every other block, so the scan alternates between
+    // recalculating rsums after a match and rolling through a miss
+    fn scan_setup() -> (Matcher, Vec<u8>) {
+        let config = Config::new(1, 5, 1024).unwrap();
+        let num_blocks = 32;
+        let target = random_target(&config, num_blocks, 5);
+
+        let mut matcher: Matcher = Matcher::new(config, num_blocks).unwrap();
+        insert_target(&config, &target, |id, block| matcher.insert_block(id, block));
+        let mut seed = target.clone();
+        for i in (0..seed.len()).step_by(2048) {
+            seed[i] ^= 0xff;
+        }
+        (matcher, seed)
+    }
+
+    #[bench]
+    fn bench_scan_scalar(b: &mut Bencher) {
+        let (matcher, seed) = scan_setup();
+        b.iter(|| matcher.scan_with(&seed, Rsum::calculate_scalar));
+    }
+
+    #[bench]
+    fn bench_scan_simd(b: &mut Bencher) {
+        let (matcher, seed) = scan_setup();
+        b.iter(|| matcher.scan_with(&seed, Rsum::calculate));
+    }
+}
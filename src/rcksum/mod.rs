@@ -1,4 +1,13 @@
 pub mod map;
 pub mod types;
 pub mod client;
+pub mod matcher;
+pub mod output;
+pub mod plan;
+#[cfg(feature = "async")]
+pub mod async_client;
 mod data_window;
+mod driver;
+mod simd;
+#[cfg(test)]
+mod test_util;
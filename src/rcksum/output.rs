@@ -0,0 +1,164 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::Path;
+use crate::error::*;
+
+// Random-access destination for the reconstructed file
+pub trait Output {
+    fn write_block_at(&mut self, offset: usize, data: &[u8]) -> Result<()>;
+    fn read_block_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()>;
+
+    // Copy len bytes from src at src_offset straight into the output, without passing them
+    // through user space. Returns false if this output can't do that, in which case the caller
+    // falls back to write_block_at.
+    fn copy_from_file(&mut self, _offset: usize, _src: &File, _src_offset: usize, _len: usize) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+impl Output for File {
+    fn write_block_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        self.seek(SeekFrom::Start(offset as u64))?;
+        let mut written = 0;
+        while written < data.len() {
+            match self.write(&data[written..]) {
+                Ok(0) => Err(Error::ShortWrite {
+                    offset,
+                    written,
+                    expected: data.len(),
+                })?,
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => Err(e)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn read_block_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        self.seek(SeekFrom::Start(offset as u64))?;
+        self.read_exact(buf)?;
+        Ok(())
+    }
+
+    // copy_file_range may reflink on btrfs/XFS instead of copying
+    #[cfg(target_os = "linux")]
+    fn copy_from_file(&mut self, offset: usize, src: &File, src_offset: usize, len: usize) -> Result<bool> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut off_in = src_offset as libc::loff_t;
+        let mut off_out = offset as libc::loff_t;
+        let mut remaining = len;
+        while remaining > 0 {
+            let copied = unsafe {
+                libc::copy_file_range(src.as_raw_fd(), &mut off_in, self.as_raw_fd(), &mut off_out, remaining, 0)
+            };
+            if copied < 0 {
+                let error = std::io::Error::last_os_error();
+                match error.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    // Not supported for this kernel, filesystem or pair of files
+                    Some(libc::ENOSYS) | Some(libc::EXDEV) | Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => return Ok(false),
+                    _ => Err(error)?,
+                }
+            }
+            if copied == 0 {
+                // The seed is shorter than expected; let the caller write the data instead
+                return Ok(false);
+            }
+            remaining -= copied as usize;
+        }
+        Ok(true)
+    }
+}
+
+// In-memory output, grown as needed
+impl Output for Vec<u8> {
+    fn write_block_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let end = offset.checked_add(data.len()).ok_or(Error::DataOutOfBounds {
+            position: offset,
+            limit: usize::MAX - data.len(),
+        })?;
+        if end > self.len() {
+            self.resize(end, 0);
+        }
+        self[offset..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_block_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let end = offset.checked_add(buf.len()).ok_or(Error::DataOutOfBounds {
+            position: offset,
+            limit: usize::MAX - buf.len(),
+        })?;
+        if end > self.len() {
+            Err(Error::DataOutOfBounds {
+                position: end,
+                limit: self.len(),
+            })?;
+        }
+        buf.copy_from_slice(&self[offset..end]);
+        Ok(())
+    }
+}
+
+// Output file mapped into memory. The file is sized up front and cannot grow.
+#[cfg(feature = "mmap")]
+pub struct MmapOutput {
+    map: memmap2::MmapMut,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapOutput {
+    pub fn create(path: &Path, len: usize) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len(len as u64)?;
+        let map = unsafe { memmap2::MmapMut::map_mut(&file)? };
+        Ok(MmapOutput { map })
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.map.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Output for MmapOutput {
+    fn write_block_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let end = offset.checked_add(data.len()).ok_or(Error::DataOutOfBounds {
+            position: offset,
+            limit: usize::MAX - data.len(),
+        })?;
+        if end > self.map.len() {
+            Err(Error::DataOutOfBounds {
+                position: end,
+                limit: self.map.len(),
+            })?;
+        }
+        self.map[offset..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_block_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let end = offset.checked_add(buf.len()).ok_or(Error::DataOutOfBounds {
+            position: offset,
+            limit: usize::MAX - buf.len(),
+        })?;
+        if end > self.map.len() {
+            Err(Error::DataOutOfBounds {
+                position: end,
+                limit: self.map.len(),
+            })?;
+        }
+        buf.copy_from_slice(&self.map[offset..end]);
+        Ok(())
+    }
+}
+
+// Create a zero-filled output file of the given length
+pub fn create_file(path: &Path, len: usize) -> Result<File> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+    file.set_len(len as u64)?;
+    Ok(file)
+}
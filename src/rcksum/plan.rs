@@ -0,0 +1,115 @@
+use std::ops::Range;
+use crate::error::*;
+use super::map::ZBlockId;
+
+// How the missing blocks are grouped into range requests. Ranges separated by no more than
+// max_gap blocks are fetched as one, since a short gap costs less than another range header,
+// and a request carries at most max_ranges ranges to stay within server header limits.
+#[derive(Copy, Clone, Debug)]
+pub struct RangePolicy {
+    max_gap: usize,
+    max_ranges: usize,
+}
+
+impl Default for RangePolicy {
+    fn default() -> Self {
+        RangePolicy {
+            max_gap: 0,
+            max_ranges: 20,
+        }
+    }
+}
+
+impl RangePolicy {
+    pub fn new(max_gap: usize, max_ranges: usize) -> Result<Self> {
+        if max_ranges == 0 {
+            Err(Error::InvalidConfig {
+                reason: "max_ranges must be non-zero",
+            })?;
+        }
+        Ok(RangePolicy { max_gap, max_ranges })
+    }
+
+    // Group needed (sorted, non-overlapping block ranges, as from Matcher::needed_ranges)
+    // into requests
+    pub fn plan(&self, needed: &[Range<ZBlockId>]) -> RangePlan {
+        let mut merged: Vec<Range<ZBlockId>> = Vec::new();
+        for range in needed.iter().filter(|r| r.start < r.end) {
+            match merged.last_mut() {
+                Some(last) if range.start.saturating_sub(last.end) <= self.max_gap => {
+                    last.end = std::cmp::max(last.end, range.end)
+                }
+                _ => merged.push(range.clone()),
+            }
+        }
+
+        let needed_blocks = needed.iter().map(|r| r.end.saturating_sub(r.start)).sum();
+        let fetched_blocks = merged.iter().map(|r| r.end - r.start).sum();
+        RangePlan {
+            requests: merged.chunks(self.max_ranges).map(|c| c.to_vec()).collect(),
+            needed_blocks,
+            fetched_blocks,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangePlan {
+    // Block ranges to ask for, one entry per request
+    pub requests: Vec<Vec<Range<ZBlockId>>>,
+    pub needed_blocks: usize,
+    pub fetched_blocks: usize,
+}
+
+impl RangePlan {
+    // Blocks fetched only because they sit in a gap that was merged over
+    pub fn overhead_blocks(&self) -> usize {
+        self.fetched_blocks.saturating_sub(self.needed_blocks)
+    }
+
+    // Gaps never include the short last block of a target, so this is exact
+    pub fn overhead_bytes(&self, blocksize: usize) -> usize {
+        self.overhead_blocks() * blocksize
+    }
+
+    // Byte ranges of a request, clipped to the length of the target file
+    pub fn byte_ranges(request: &[Range<ZBlockId>], blocksize: usize, file_len: usize) -> Vec<Range<usize>> {
+        request.iter()
+            .map(|r| r.start * blocksize..std::cmp::min(r.end * blocksize, file_len))
+            .filter(|r| r.start < r.end)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_and_split() {
+        let needed = vec![0..2, 3..4, 10..12, 14..15, 30..31];
+
+        let plan = RangePolicy::default().plan(&needed);
+        assert!(plan.requests == vec![needed.clone()]);
+        assert!(plan.overhead_blocks() == 0);
+
+        let plan = RangePolicy::new(2, 20).unwrap().plan(&needed);
+        assert!(plan.requests == vec![vec![0..4, 10..15, 30..31]]);
+        assert!(plan.needed_blocks == 7 && plan.fetched_blocks == 10);
+        assert!(plan.overhead_bytes(2048) == 3 * 2048);
+
+        let plan = RangePolicy::new(1, 2).unwrap().plan(&needed);
+        assert!(plan.requests == vec![vec![0..4, 10..12], vec![14..15, 30..31]]);
+
+        assert!(RangePolicy::new(1, 0).is_err());
+        assert!(RangePolicy::default().plan(&[]).requests.is_empty());
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let request = vec![0..2, 4..5];
+        assert!(RangePlan::byte_ranges(&request, 16, 70) == vec![0..32, 64..70]);
+        assert!(RangePlan::byte_ranges(&request, 16, 64) == vec![0..32]);
+    }
+}
@@ -0,0 +1,139 @@
+extern crate test;
+use super::types::Rsum;
+
+// Vectorised Rsum::calculate. Both halves of the rsum are sums modulo 2^16, so they can be
+// accumulated in wrapping u16 lanes and summed horizontally at the end:
+//   a = sum(x[i])
+//   b = sum((len - i) * x[i])
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn calculate(data: &[u8]) -> Rsum {
+    if is_x86_feature_detected!("avx2") {
+        unsafe { x86::calculate_avx2(data) }
+    } else if is_x86_feature_detected!("sse2") {
+        unsafe { x86::calculate_sse2(data) }
+    } else {
+        Rsum::calculate_scalar(data)
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub fn calculate(data: &[u8]) -> Rsum {
+    Rsum::calculate_scalar(data)
+}
+
+// Fold the bytes after the vectorised prefix into the rsum
+#[inline]
+fn finish(mut a: u16, mut b: u16, tail: &[u8]) -> Rsum {
+    let mut weight = tail.len() as u16;
+    for &x in tail {
+        a = a.wrapping_add(x.into());
+        b = b.wrapping_add(weight.wrapping_mul(x.into()));
+        weight = weight.wrapping_sub(1);
+    }
+    Rsum(a, b)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+    use super::*;
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn calculate_sse2(data: &[u8]) -> Rsum {
+        let len = data.len() as u16;
+        let zero = _mm_setzero_si128();
+        let mut acc_a = zero;
+        let mut acc_b = zero;
+        // Weights of the low and high eight bytes of the first chunk
+        let mut w_lo = _mm_sub_epi16(_mm_set1_epi16(len as i16), _mm_setr_epi16(0, 1, 2, 3, 4, 5, 6, 7));
+        let mut w_hi = _mm_sub_epi16(_mm_set1_epi16(len as i16), _mm_setr_epi16(8, 9, 10, 11, 12, 13, 14, 15));
+        let step = _mm_set1_epi16(16);
+
+        let mut chunks = data.chunks_exact(16);
+        for chunk in &mut chunks {
+            let x = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+            let lo = _mm_unpacklo_epi8(x, zero);
+            let hi = _mm_unpackhi_epi8(x, zero);
+            acc_a = _mm_add_epi16(acc_a, _mm_add_epi16(lo, hi));
+            acc_b = _mm_add_epi16(acc_b, _mm_add_epi16(_mm_mullo_epi16(lo, w_lo), _mm_mullo_epi16(hi, w_hi)));
+            w_lo = _mm_sub_epi16(w_lo, step);
+            w_hi = _mm_sub_epi16(w_hi, step);
+        }
+
+        let mut a = [0u16; 8];
+        let mut b = [0u16; 8];
+        _mm_storeu_si128(a.as_mut_ptr() as *mut __m128i, acc_a);
+        _mm_storeu_si128(b.as_mut_ptr() as *mut __m128i, acc_b);
+        let a = a.iter().fold(0u16, |acc, x| acc.wrapping_add(*x));
+        let b = b.iter().fold(0u16, |acc, x| acc.wrapping_add(*x));
+        finish(a, b, chunks.remainder())
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn calculate_avx2(data: &[u8]) -> Rsum {
+        let len = data.len() as u16;
+        let mut acc_a = _mm256_setzero_si256();
+        let mut acc_b = _mm256_setzero_si256();
+        // Weights of the low and high sixteen bytes of the first chunk
+        let offsets = _mm256_setr_epi16(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+        let mut w_lo = _mm256_sub_epi16(_mm256_set1_epi16(len as i16), offsets);
+        let mut w_hi = _mm256_sub_epi16(w_lo, _mm256_set1_epi16(16));
+        let step = _mm256_set1_epi16(32);
+
+        let mut chunks = data.chunks_exact(32);
+        for chunk in &mut chunks {
+            let lo = _mm256_cvtepu8_epi16(_mm_loadu_si128(chunk.as_ptr() as *const __m128i));
+            let hi = _mm256_cvtepu8_epi16(_mm_loadu_si128(chunk.as_ptr().add(16) as *const __m128i));
+            acc_a = _mm256_add_epi16(acc_a, _mm256_add_epi16(lo, hi));
+            acc_b = _mm256_add_epi16(acc_b, _mm256_add_epi16(_mm256_mullo_epi16(lo, w_lo), _mm256_mullo_epi16(hi, w_hi)));
+            w_lo = _mm256_sub_epi16(w_lo, step);
+            w_hi = _mm256_sub_epi16(w_hi, step);
+        }
+
+        let mut a = [0u16; 16];
+        let mut b = [0u16; 16];
+        _mm256_storeu_si256(a.as_mut_ptr() as *mut __m256i, acc_a);
+        _mm256_storeu_si256(b.as_mut_ptr() as *mut __m256i, acc_b);
+        let a = a.iter().fold(0u16, |acc, x| acc.wrapping_add(*x));
+        let b = b.iter().fold(0u16, |acc, x| acc.wrapping_add(*x));
+        finish(a, b, chunks.remainder())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test::Bencher;
+    use crate::rcksum::test_util::random_data;
+
+    #[test]
+    fn matches_scalar() {
+        let data = random_data(11, 5000);
+        for len in (0..100).chain(vec![1024, 2048, 4095, 4096, 4999]) {
+            let expected = Rsum::calculate_scalar(&data[..len]);
+            assert!(calculate(&data[..len]) == expected);
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            {
+                assert!(unsafe { x86::calculate_sse2(&data[..len]) } == expected);
+                if is_x86_feature_detected!("avx2") {
+                    assert!(unsafe { x86::calculate_avx2(&data[..len]) } == expected);
+                }
+            }
+        }
+    }
+
+    #[bench]
+    fn bench_scalar(b: &mut Bencher) {
+        let data = random_data(11, 4096);
+        b.iter(|| Rsum::calculate_scalar(test::black_box(&data)));
+    }
+
+    #[bench]
+    fn bench_simd(b: &mut Bencher) {
+        let data = random_data(11, 4096);
+        b.iter(|| calculate(test::black_box(&data)));
+    }
+}
@@ -0,0 +1,60 @@
+// Fixtures shared by the rcksum tests
+use super::map::{ZBlock, ZBlockId};
+use super::matcher::Config;
+use super::types::*;
+use crate::error::Result;
+
+// A target of three 16 byte blocks, the first and last identical
+pub const SMALL_TARGET: [[u8; 16]; 3] = [[1; 16], [2; 16], [1; 16]];
+
+pub fn small_config() -> Config {
+    Config::new(1, 5, 16).unwrap()
+}
+
+pub fn small_target() -> Vec<u8> {
+    SMALL_TARGET.concat()
+}
+
+// Register the blocks of SMALL_TARGET through insert
+pub fn insert_small_target<F: FnMut(ZBlockId, ZBlock) -> Result<()>>(insert: F) {
+    insert_target(&small_config(), &small_target(), insert);
+}
+
+// A target of num_blocks random blocks
+pub fn random_target(config: &Config, num_blocks: usize, seed: u32) -> Vec<u8> {
+    random_data(seed, num_blocks * config.blocksize())
+}
+
+// Register every block of target through insert, with checksums as long as config asks for.
+// A short last block is padded with zeros.
+pub fn insert_target<F: FnMut(ZBlockId, ZBlock) -> Result<()>>(config: &Config, target: &[u8], mut insert: F) {
+    for (id, data) in target.chunks(config.blocksize()).enumerate() {
+        let mut data = data.to_vec();
+        data.resize(config.blocksize(), 0);
+        insert(id, checksums(&data, config.checksum_bytes())).unwrap();
+    }
+}
+
+// Checksums of a block, truncated to the 5 bytes the small configs use
+pub fn block(data: &[u8]) -> ZBlock {
+    checksums(data, 5)
+}
+
+fn checksums(data: &[u8], length: usize) -> ZBlock {
+    ZBlock {
+        rsum: Rsum::calculate(data),
+        checksum: PartialChecksum {
+            value: MD4Digest::calculate(data),
+            length,
+        },
+    }
+}
+
+// Repeatable pseudo-random data from a linear congruential generator
+pub fn random_data(seed: u32, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    (0..len).map(|_| {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (state >> 16) as u8
+    }).collect()
+}
@@ -1,6 +1,8 @@
+use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
 use std::num::Wrapping;
 use md4::{Md4, Digest};
+use crate::error::*;
 
 #[derive(Copy, Clone, Debug, Default)]
 pub struct Rsum(pub u16, pub u16);
@@ -17,31 +19,38 @@ impl Eq for Rsum {}
 impl Hash for Rsum {
     #[inline]
     fn hash<H: Hasher>(&self, state: &mut H) {
-        state.write_u16(<u16>::from(self.0) + <u16>::from(self.1));
+        state.write_u32((u32::from(self.0) << 16) | u32::from(self.1));
     }
 }
 
 impl Rsum {
-    // Calculate the checksum of a block
+    // Calculate the checksum of a block, using SIMD where the CPU supports it
     #[inline]
     pub fn calculate(data: &[u8]) -> Self {
-        let result = data.iter().fold((Rsum(0, 0), data.len() as u16), |acc, x| {
-            let a = Wrapping((acc.0).0);
-            let b = Wrapping((acc.0).1);
-            let len = Wrapping(acc.1);
-            let x = Wrapping((*x).into());
-            (Rsum((a + x).0, (b + (len * x)).0), acc.1 - 1)
-        });
-        assert!(result.1 == 0);
-        result.0
+        super::simd::calculate(data)
+    }
+
+    // Portable byte-at-a-time version of calculate
+    #[inline]
+    pub fn calculate_scalar(data: &[u8]) -> Self {
+        // Each byte is weighted by its distance from the end of the block
+        let (a, b, _) = data.iter().fold(
+            (Wrapping(0u16), Wrapping(0u16), Wrapping(data.len() as u16)),
+            |(a, b, weight), x| {
+                let x = Wrapping(<u16>::from(*x));
+                (a + x, b + weight * x, weight - Wrapping(1))
+            },
+        );
+        Rsum(a.0, b.0)
     }
 
     // Update the rolling checksum with the next byte
     #[inline]
-    pub fn update(&mut self, old: u8, new: u8, blocksize: u8) {
+    pub fn update(&mut self, old: u8, new: u8, blocksize: usize) {
         let old = Wrapping(<u16>::from(old));
         let new = Wrapping(<u16>::from(new));
-        let blocksize = Wrapping(<u16>::from(blocksize));
+        // Only the blocksize modulo 2^16 matters
+        let blocksize = Wrapping(blocksize as u16);
         let a = Wrapping(self.0) - old + new;
         let b = Wrapping(self.1) - (old * blocksize) + a;
         self.0 = a.0;
@@ -49,37 +58,61 @@ impl Rsum {
     }
 }
 
+// Strong checksum used to confirm a weak (rsum) hit. MD4 is what existing control files
+// carry; other digests can be plugged in for new control-file variants.
+pub trait StrongHash: Copy + Clone + Debug + Default + PartialEq + Send + Sync {
+    // Full length of the digest in bytes
+    const LENGTH: usize;
+
+    fn calculate(data: &[u8]) -> Self;
+    fn as_bytes(&self) -> &[u8];
+}
+
 #[derive(Copy, Clone, Debug, Default)]
-pub struct PartialChecksum {
-    pub value: MD4Digest,
+pub struct PartialChecksum<H: StrongHash = MD4Digest> {
+    pub value: H,
     pub length: usize,
 }
 
-impl PartialEq for PartialChecksum {
+impl<H: StrongHash> PartialChecksum<H> {
+    pub fn new(value: H, length: usize) -> Result<Self> {
+        if length == 0 || length > H::LENGTH {
+            Err(Error::InvalidChecksumLength {
+                length,
+                max: H::LENGTH,
+            })?;
+        }
+        Ok(PartialChecksum { value, length })
+    }
+
+    // The compared prefix of the digest. Lengths past the end of the digest, which new()
+    // rejects but the public fields allow, are clamped to the whole digest.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        let bytes = self.value.as_bytes();
+        &bytes[..std::cmp::min(self.length, bytes.len())]
+    }
+}
+
+impl<H: StrongHash> PartialEq for PartialChecksum<H> {
     #[inline]
     fn eq(&self, other: &Self) -> bool {
-        unsafe {
-            (0 == libc::memcmp(self.value.0.as_ptr() as *const _, other.value.0.as_ptr() as *const _, self.length))
-        }
+        self.as_bytes() == other.as_bytes()
     }
 }
 
-impl Eq for PartialChecksum {}
+impl<H: StrongHash> Eq for PartialChecksum<H> {}
 
-impl Hash for PartialChecksum {
+impl<H: StrongHash> Hash for PartialChecksum<H> {
     #[inline]
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        Hash::hash_slice(&self.value.0[0..self.length], state);
+    fn hash<I: Hasher>(&self, state: &mut I) {
+        Hash::hash_slice(self.as_bytes(), state);
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub struct MD4Digest(pub [u8; 16]);
 
-impl Default for MD4Digest {
-    fn default() -> Self { MD4Digest([0; 16]) }
-}
-
 impl From<[u8; 16]> for MD4Digest {
     fn from(x: [u8; 16]) -> Self {
         MD4Digest(x)
@@ -95,3 +128,97 @@ impl MD4Digest {
         result
     }
 }
+
+impl StrongHash for MD4Digest {
+    const LENGTH: usize = 16;
+
+    #[inline]
+    fn calculate(data: &[u8]) -> Self {
+        MD4Digest::calculate(data)
+    }
+
+    #[inline]
+    fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(feature = "sha256")]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Sha256Digest(pub [u8; 32]);
+
+#[cfg(feature = "sha256")]
+impl From<[u8; 32]> for Sha256Digest {
+    fn from(x: [u8; 32]) -> Self {
+        Sha256Digest(x)
+    }
+}
+
+#[cfg(feature = "sha256")]
+impl StrongHash for Sha256Digest {
+    const LENGTH: usize = 32;
+
+    fn calculate(data: &[u8]) -> Self {
+        let mut hasher = sha2::Sha256::new();
+        hasher.input(data);
+        let mut result = Sha256Digest([0; 32]);
+        result.0.copy_from_slice(hasher.result().as_slice());
+        result
+    }
+
+    #[inline]
+    fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(feature = "blake3")]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Blake3Digest(pub [u8; 32]);
+
+#[cfg(feature = "blake3")]
+impl From<[u8; 32]> for Blake3Digest {
+    fn from(x: [u8; 32]) -> Self {
+        Blake3Digest(x)
+    }
+}
+
+#[cfg(feature = "blake3")]
+impl StrongHash for Blake3Digest {
+    const LENGTH: usize = 32;
+
+    fn calculate(data: &[u8]) -> Self {
+        Blake3Digest(*blake3::hash(data).as_bytes())
+    }
+
+    #[inline]
+    fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_checksum() {
+        let value = MD4Digest::calculate(&[1; 16]);
+        let mut other = value;
+        other.0[6] ^= 0xff;
+
+        assert!(PartialChecksum::new(value, 5).unwrap() == PartialChecksum::new(other, 5).unwrap());
+        assert!(PartialChecksum::new(value, 7).unwrap() != PartialChecksum::new(other, 7).unwrap());
+        // Checksums of different lengths never compare equal
+        assert!(PartialChecksum::new(value, 5).unwrap() != PartialChecksum::new(value, 6).unwrap());
+
+        assert!(PartialChecksum::new(value, 16).is_ok());
+        assert!(PartialChecksum::new(value, 0).is_err());
+        assert!(PartialChecksum::new(value, 17).is_err());
+
+        // Out of range lengths built by hand compare as the whole digest
+        let bad = PartialChecksum { value, length: 100 };
+        assert!(bad == bad);
+        assert!(bad == PartialChecksum::new(value, 16).unwrap());
+    }
+}